@@ -0,0 +1,136 @@
+// Sends many small requests through an rkp HTTP listener, first with idle
+// upstream connections disabled, so every request dials the origin through
+// the chain as the listener used to, then with the shared client pool.
+//
+//     cargo bench --bench http_pool
+
+use std::{
+    convert::Infallible,
+    net::{SocketAddr, TcpListener},
+    path::PathBuf,
+    process::{Child, Command, Stdio},
+    time::{Duration, Instant},
+};
+
+use hyper::{
+    header,
+    service::{make_service_fn, service_fn},
+    Body, Client, Request, Response, Server, Uri,
+};
+
+const REQUESTS: usize = 2000;
+
+async fn serve() -> SocketAddr {
+    let make_service = make_service_fn(|_| async {
+        Ok::<_, Infallible>(service_fn(|_: Request<Body>| async {
+            Ok::<_, Infallible>(Response::new(Body::from("hello")))
+        }))
+    });
+    let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+    let address = server.local_addr();
+    tokio::spawn(server);
+    address
+}
+
+fn free_address() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
+
+struct Rkp {
+    child: Child,
+    config: PathBuf,
+    listener: SocketAddr,
+}
+
+impl Drop for Rkp {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_file(&self.config);
+    }
+}
+
+// The origin listens on loopback, so the chain has to allow internal
+// destinations.
+async fn start_rkp(name: &str, http_pool: &str) -> Rkp {
+    let config =
+        std::env::temp_dir().join(format!("rkp-bench-{}-{}.json", name, std::process::id()));
+    std::fs::write(
+        &config,
+        format!(
+            r#"{{"chains":{{"default":[{{"action":"DirectConnect","allow_internal":true}}]}},"http_pool":{}}}"#,
+            http_pool
+        ),
+    )
+    .unwrap();
+    let listener = free_address();
+    let child = Command::new(env!("CARGO_BIN_EXE_rkp"))
+        .arg("--config")
+        .arg(&config)
+        .arg("--logging")
+        .arg("none")
+        .arg("--control")
+        .arg(free_address().to_string())
+        .arg("--bind")
+        .arg(format!("kind=http,addr={},chain=default", listener))
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let rkp = Rkp {
+        child,
+        config,
+        listener,
+    };
+    while tokio::net::TcpStream::connect(rkp.listener).await.is_err() {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    rkp
+}
+
+async fn run(rkp: &Rkp, origin: SocketAddr) -> Duration {
+    let client = Client::new();
+    let uri: Uri = format!("http://{}/", rkp.listener).parse().unwrap();
+    let start = Instant::now();
+    for _ in 0..REQUESTS {
+        let req = Request::get(uri.clone())
+            .header(header::HOST, origin.to_string())
+            .body(Body::empty())
+            .unwrap();
+        let response = client.request(req).await.unwrap();
+        assert!(response.status().is_success());
+        hyper::body::to_bytes(response.into_body()).await.unwrap();
+    }
+    start.elapsed()
+}
+
+#[tokio::main]
+async fn main() {
+    let origin = serve().await;
+
+    let rkp = start_rkp("unpooled", r#"{"max_idle_per_host":0}"#).await;
+    let unpooled = run(&rkp, origin).await;
+    drop(rkp);
+
+    let rkp = start_rkp("pooled", "{}").await;
+    let pooled = run(&rkp, origin).await;
+    drop(rkp);
+
+    println!(
+        "connection per request: {:?} ({:?}/request)",
+        unpooled,
+        unpooled / REQUESTS as u32
+    );
+    println!(
+        "pooled connections:     {:?} ({:?}/request)",
+        pooled,
+        pooled / REQUESTS as u32
+    );
+    println!(
+        "speedup:                {:.1}x",
+        unpooled.as_secs_f64() / pooled.as_secs_f64()
+    );
+}
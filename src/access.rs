@@ -0,0 +1,74 @@
+use std::net::{IpAddr, SocketAddr};
+
+use crate::{
+    args,
+    config::{self, ClientRange},
+    metrics::METRICS,
+};
+
+pub async fn allowed(listener: &args::Listener, client: SocketAddr) -> bool {
+    let allowed = check(listener, client).await;
+    if !allowed {
+        log::info!("rejected client {} on {}", client, listener.addr);
+    }
+    allowed
+}
+
+// The global deny list is checked first, then the listener's deny list. A
+// listener with an allow list only accepts the clients on it. Rejections are
+// counted but not logged, for listeners that see the same client once per
+// datagram.
+pub async fn check(listener: &args::Listener, client: SocketAddr) -> bool {
+    let config = config::get_current_config().await;
+    let address = client.ip().to_canonical();
+    let counter = if matches_any(&config, &config.access.deny, address) {
+        &METRICS.rejected_by_global_deny
+    } else if matches_any(&config, &listener.deny, address) {
+        &METRICS.rejected_by_deny
+    } else if !listener.allow.is_empty() && !matches_any(&config, &listener.allow, address) {
+        &METRICS.rejected_by_allow
+    } else {
+        return true;
+    };
+    counter.inc();
+    false
+}
+
+fn matches_any(config: &config::Config, ranges: &[ClientRange], address: IpAddr) -> bool {
+    ranges.iter().any(|range| match range {
+        ClientRange::Net(net) => net.contains(&address),
+        ClientRange::Pool(pool) => match config.stash.ip_pools.get(pool) {
+            Some(pool) => pool.0.iter().any(|net| net.contains(&address)),
+            None => {
+                log::warn!("ip pool \"{}\" not found in configuration", pool);
+                false
+            }
+        },
+    })
+}
+
+#[test]
+fn matches_any_test() {
+    let mut config = config::Config::default();
+    config.stash.ip_pools.insert(
+        "office".to_owned(),
+        config::IpPool(["192.168.0.0/16".parse().unwrap()].into()),
+    );
+    let ranges: Vec<ClientRange> = ["10.0.0.0/8", "2001:db8::1", "pool:office"]
+        .into_iter()
+        .map(|range| range.parse().unwrap())
+        .collect();
+    assert_eq!(
+        ranges[1],
+        ClientRange::Net("2001:db8::1/128".parse().unwrap())
+    );
+    let matches = |address: &str| matches_any(&config, &ranges, address.parse().unwrap());
+    assert!(matches("10.1.2.3"));
+    assert!(matches("2001:db8::1"));
+    assert!(matches("192.168.1.1"));
+    assert!(!matches("172.16.0.1"));
+    assert!(!matches("2001:db8::2"));
+    assert!("10.0.0.0/33".parse::<ClientRange>().is_err());
+    assert!("office".parse::<ClientRange>().is_err());
+    assert!(serde_json::from_str::<ClientRange>("\"10.0.0.0/33\"").is_err());
+}
@@ -1,22 +1,42 @@
 use std::{
     net::{IpAddr, Ipv4Addr, SocketAddr},
     str::FromStr,
+    time::Duration,
 };
 
 use clap::Parser;
+use humantime_serde::re::humantime;
+
+use crate::config::{ClientRange, HttpForwarding, Limits, Timeouts};
 
 #[derive(Debug, Clone)]
 pub struct Listener {
     pub kind: ListenerKind,
     pub addr: SocketAddr,
     pub chain: String,
+    pub timeouts: Timeouts,
+    pub port: Option<u16>,
+    pub fallback: Option<String>,
+    pub transparent: bool,
+    pub upstream_tls: bool,
+    pub sniff_login: bool,
+    pub auth: bool,
+    pub allow: Vec<ClientRange>,
+    pub deny: Vec<ClientRange>,
+    pub limits: Limits,
+    pub max_connections: Option<usize>,
+    pub http: HttpForwarding,
 }
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Clone)]
 pub enum ListenerKind {
     HTTP,
     TLS,
+    TLSTerminate,
     MC,
+    Bedrock,
+    SOCKS5,
 }
 
 impl FromStr for Listener {
@@ -26,6 +46,18 @@ impl FromStr for Listener {
         let mut kind = ListenerKind::HTTP;
         let mut addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 8080);
         let mut chain: Option<&str> = None;
+        let mut timeouts = Timeouts::default();
+        let mut port = None;
+        let mut fallback = None;
+        let mut transparent = false;
+        let mut upstream_tls = false;
+        let mut sniff_login = false;
+        let mut auth = false;
+        let mut allow = Vec::new();
+        let mut deny = Vec::new();
+        let mut limits = Limits::default();
+        let mut max_connections = None;
+        let mut http = HttpForwarding::default();
         let params = s.split(",").map(|it| it.trim()).filter(|it| !it.is_empty());
         for param in params {
             let (key, value) = match param.find("=") {
@@ -50,20 +82,84 @@ impl FromStr for Listener {
                     }
                 }
                 "chain" | "c" => chain = Some(value),
+                "handshake_timeout" => timeouts.handshake = Some(parse_duration(key, value)?),
+                "connect_timeout" => timeouts.connect = Some(parse_duration(key, value)?),
+                "socks_timeout" => timeouts.socks = Some(parse_duration(key, value)?),
+                "idle_timeout" => timeouts.idle = Some(parse_duration(key, value)?),
+                "port" => port = Some(parse_value(key, value)?),
+                "fallback" => fallback = Some(value.to_owned()),
+                "transparent" => transparent = parse_value(key, value)?,
+                "upstream_tls" => upstream_tls = parse_value(key, value)?,
+                "sniff_login" => sniff_login = parse_value(key, value)?,
+                "auth" => auth = parse_value(key, value)?,
+                "allow" => allow.push(parse_value(key, value)?),
+                "deny" => deny.push(parse_value(key, value)?),
+                "connection_rate" => limits.connection_rate = Some(parse_positive(key, value)?),
+                "max_client_connections" => {
+                    limits.max_client_connections = Some(parse_value(key, value)?)
+                }
+                "max_connections" => max_connections = Some(parse_value(key, value)?),
+                "upload" => limits.upload = Some(parse_positive(key, value)?),
+                "download" => limits.download = Some(parse_positive(key, value)?),
+                "via" => http.via = Some(parse_value(key, value)?),
+                "forwarded" => http.forwarded = Some(parse_value(key, value)?),
+                "x_forwarded" => http.x_forwarded = Some(parse_value(key, value)?),
+                "hide_client" => http.hide_client = Some(parse_value(key, value)?),
                 _ => return Err(anyhow::anyhow!("unknown parameter \"{}\"", key)),
             }
         }
         match chain {
             Some(chain) => Ok(Listener {
-                kind: kind,
-                addr: addr,
+                kind,
+                addr,
                 chain: chain.to_owned(),
+                timeouts,
+                port,
+                fallback,
+                transparent,
+                upstream_tls,
+                sniff_login,
+                auth,
+                allow,
+                deny,
+                limits,
+                max_connections,
+                http,
             }),
             None => Err(anyhow::anyhow!("\"chain\" parameter is required")),
         }
     }
 }
 
+fn parse_value<T>(key: &str, value: &str) -> anyhow::Result<T>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    FromStr::from_str(value).map_err(|error| anyhow::anyhow!("in parameter \"{}\": {}", key, error))
+}
+
+fn parse_positive<T>(key: &str, value: &str) -> anyhow::Result<T>
+where
+    T: FromStr + PartialOrd + Default,
+    T::Err: std::fmt::Display,
+{
+    let parsed: T = parse_value(key, value)?;
+    if parsed > T::default() {
+        Ok(parsed)
+    } else {
+        Err(anyhow::anyhow!(
+            "in parameter \"{}\": expected a positive number",
+            key
+        ))
+    }
+}
+
+fn parse_duration(key: &str, value: &str) -> anyhow::Result<std::time::Duration> {
+    humantime::parse_duration(value)
+        .map_err(|error| anyhow::anyhow!("in parameter \"{}\": {}", key, error))
+}
+
 impl FromStr for ListenerKind {
     type Err = anyhow::Error;
 
@@ -72,7 +168,10 @@ impl FromStr for ListenerKind {
         match &lower[..] {
             "http" => Ok(ListenerKind::HTTP),
             "tls" => Ok(ListenerKind::TLS),
+            "tls-terminate" => Ok(ListenerKind::TLSTerminate),
             "mc" => Ok(ListenerKind::MC),
+            "bedrock" => Ok(ListenerKind::Bedrock),
+            "socks5" => Ok(ListenerKind::SOCKS5),
             _ => Err(anyhow::anyhow!("unknown listener kind \"{}\"", s)),
         }
     }
@@ -92,6 +191,16 @@ pub struct Args {
 
     #[arg(short, long, num_args = 0..)]
     pub bind: Vec<Listener>,
+
+    #[arg(long)]
+    pub max_connections: Option<usize>,
+
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "30s")]
+    pub drain_timeout: Duration,
+
+    // `POST /shutdown` is refused unless a token is set.
+    #[arg(long, env = "RKP_CONTROL_TOKEN", hide_env_values = true)]
+    pub control_token: Option<String>,
 }
 
 impl Args {
@@ -103,3 +212,21 @@ impl Args {
 lazy_static::lazy_static! {
     static ref ARGS: Args = Args::parse();
 }
+
+#[test]
+fn listener_parse_test() {
+    let listener: Listener =
+        "kind=tls,addr=127.0.0.1:8443,chain=tls,port=443,fallback=example.com:443,transparent=true"
+            .parse()
+            .unwrap();
+    assert!(matches!(listener.kind, ListenerKind::TLS));
+    assert_eq!(listener.addr, "127.0.0.1:8443".parse().unwrap());
+    assert_eq!(listener.port, Some(443));
+    assert_eq!(listener.fallback.as_deref(), Some("example.com:443"));
+    assert!(listener.transparent);
+    assert!("kind=tls,chain=tls,port=65536".parse::<Listener>().is_err());
+    assert!("kind=tls,chain=tls,transparent=yes"
+        .parse::<Listener>()
+        .is_err());
+    assert!("kind=tls,port=443".parse::<Listener>().is_err());
+}
@@ -0,0 +1,82 @@
+use std::{collections::HashSet, sync::Mutex};
+
+use argon2::{password_hash::PasswordHash, Argon2, PasswordVerifier};
+use sha2::{Digest, Sha256};
+
+use crate::config;
+
+const MAX_VERIFIED: usize = 1024;
+
+// Password hashes are slow to check by design, so successful checks are
+// remembered. The stored hash is part of the key, which makes a password
+// change invalidate them.
+lazy_static::lazy_static! {
+    static ref VERIFIED: Mutex<HashSet<[u8; 32]>> = Mutex::new(HashSet::new());
+}
+
+pub async fn authenticate(username: &str, password: &str) -> Option<config::User> {
+    let config = config::get_current_config().await;
+    let user = match config.users.get(username) {
+        Some(user) => user.clone(),
+        None => {
+            log::info!("authentication failed for unknown user \"{}\"", username);
+            return None;
+        }
+    };
+    let key: [u8; 32] = Sha256::new()
+        .chain_update(user.password.as_bytes())
+        .chain_update([0])
+        .chain_update(password.as_bytes())
+        .finalize()
+        .into();
+    if VERIFIED.lock().unwrap().contains(&key) {
+        return Some(user);
+    }
+    let hash = user.password.clone();
+    let password = password.to_owned();
+    let verified = tokio::task::spawn_blocking(move || verify(&hash, &password))
+        .await
+        .unwrap_or(false);
+    if !verified {
+        log::info!("authentication failed for user \"{}\"", username);
+        return None;
+    }
+    let mut verified = VERIFIED.lock().unwrap();
+    if verified.len() >= MAX_VERIFIED {
+        verified.clear();
+    }
+    verified.insert(key);
+    Some(user)
+}
+
+fn verify(hash: &str, password: &str) -> bool {
+    if hash.starts_with("$2") {
+        return bcrypt::verify(password, hash).unwrap_or(false);
+    }
+    match PasswordHash::new(hash) {
+        Ok(hash) => Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok(),
+        Err(error) => {
+            log::warn!("bad password hash in configuration: {}", error);
+            false
+        }
+    }
+}
+
+#[test]
+fn verify_test() {
+    use argon2::{password_hash::SaltString, PasswordHasher};
+
+    let salt = SaltString::encode_b64(b"0123456789abcdef").unwrap();
+    let argon2 = Argon2::default()
+        .hash_password(b"secret", &salt)
+        .unwrap()
+        .to_string();
+    assert!(verify(&argon2, "secret"));
+    assert!(!verify(&argon2, "wrong"));
+    let bcrypt = bcrypt::hash("secret", 4).unwrap();
+    assert!(verify(&bcrypt, "secret"));
+    assert!(!verify(&bcrypt, "wrong"));
+    assert!(!verify("plain", "plain"));
+}
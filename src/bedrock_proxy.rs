@@ -0,0 +1,324 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::Context as _;
+use tokio::{net::UdpSocket, sync::Mutex};
+
+use crate::{
+    access, args,
+    chain::{self, Context},
+    config::{AddressFamily, ChainAction},
+    connections, dns, limits,
+    relay::Activity,
+    shutdown,
+};
+
+const UNCONNECTED_PING: u8 = 0x01;
+const UNCONNECTED_PING_OPEN_CONNECTIONS: u8 = 0x02;
+const OPEN_CONNECTION_REQUEST_1: u8 = 0x05;
+const OFFLINE_MESSAGE_MAGIC: [u8; 16] = [
+    0x00, 0xff, 0xff, 0x00, 0xfe, 0xfe, 0xfe, 0xfe, 0xfd, 0xfd, 0xfd, 0xfd, 0x12, 0x34, 0x56, 0x78,
+];
+const DEFAULT_SESSION_IDLE: Duration = Duration::from_secs(60);
+// Every session holds an upstream socket, so the listener caps them even
+// when `max_connections` is not set.
+const DEFAULT_MAX_SESSIONS: usize = 1024;
+const MAX_DATAGRAM_SIZE: usize = 65535;
+const DRAIN_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// `upload` polices what the client sends, `download` what the upstream sends.
+struct Session {
+    upstream: UdpSocket,
+    activity: Activity,
+    upload: limits::Policer,
+    download: limits::Policer,
+}
+
+type Sessions = Arc<Mutex<HashMap<SocketAddr, Arc<Session>>>>;
+
+pub async fn actor(listener: args::Listener) -> anyhow::Result<()> {
+    let socket = Arc::new(UdpSocket::bind(&listener.addr).await?);
+    // Datagrams from clients with a session keep being relayed while
+    // connections drain, so the receive loop runs as a tracked task.
+    shutdown::spawn(receive(socket, listener));
+    shutdown::cancelled().await;
+    Ok(())
+}
+
+// During shutdown only existing sessions are served, and the loop ends once
+// the last of them closed.
+async fn receive(socket: Arc<UdpSocket>, listener: args::Listener) {
+    let sessions = Sessions::default();
+    let connections = connections::Limit::new(Some(
+        listener.max_connections.unwrap_or(DEFAULT_MAX_SESSIONS),
+    ));
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+
+    loop {
+        let draining = shutdown::is_draining();
+        if draining && sessions.lock().await.is_empty() {
+            return;
+        }
+        let received = tokio::select! {
+            received = socket.recv_from(&mut buf) => received,
+            _ = shutdown::cancelled(), if !draining => continue,
+            _ = tokio::time::sleep(DRAIN_CHECK_INTERVAL), if draining => continue,
+        };
+        let (length, client_addr) = match received {
+            Ok(received) => received,
+            Err(error) => {
+                log::debug!("failed to receive Bedrock datagram: {}", error);
+                continue;
+            }
+        };
+        let packet = &buf[..length];
+        let session = sessions.lock().await.get(&client_addr).cloned();
+        match session {
+            Some(session) => {
+                session.activity.touch();
+                if !session.upload.allow(length) {
+                    continue;
+                }
+                if let Err(error) = session.upstream.send(packet).await {
+                    log::debug!("failed to forward Bedrock datagram: {}", error);
+                }
+            }
+            None if draining => {
+                log::debug!(
+                    "shutting down; drop new Bedrock session from {}",
+                    &client_addr
+                )
+            }
+            None if starts_session(packet) => {
+                if !access::check(&listener, client_addr).await {
+                    log::debug!("rejected Bedrock client {}", &client_addr);
+                    continue;
+                }
+                let permit = match connections.try_acquire(client_addr) {
+                    Some(permit) => permit,
+                    None => continue,
+                };
+                let socket = socket.clone();
+                let sessions = sessions.clone();
+                let listener = listener.clone();
+                let packet = packet.to_vec();
+                shutdown::spawn(async move {
+                    let _permit = permit;
+                    log::debug!("Bedrock session started: {}", &client_addr);
+                    if let Err(error) =
+                        proxy(socket, sessions, &listener, client_addr, packet).await
+                    {
+                        log::debug!("an error occurred in Bedrock session; error = {}", error);
+                    }
+                });
+            }
+            None => log::debug!("unexpected Bedrock datagram from {}; drop", &client_addr),
+        }
+    }
+}
+
+// Sessions are only opened by RakNet offline messages carrying the magic, so
+// stray datagrams do not allocate upstream sockets.
+fn starts_session(packet: &[u8]) -> bool {
+    let magic_offset = match packet.first() {
+        Some(&UNCONNECTED_PING) | Some(&UNCONNECTED_PING_OPEN_CONNECTIONS) => 9,
+        Some(&OPEN_CONNECTION_REQUEST_1) => 1,
+        _ => return false,
+    };
+    packet.get(magic_offset..magic_offset + OFFLINE_MESSAGE_MAGIC.len())
+        == Some(&OFFLINE_MESSAGE_MAGIC[..])
+}
+
+async fn proxy(
+    socket: Arc<UdpSocket>,
+    sessions: Sessions,
+    listener: &args::Listener,
+    client_addr: SocketAddr,
+    packet: Vec<u8>,
+) -> anyhow::Result<()> {
+    let context = session_context(listener, client_addr)?;
+    let route = chain::route(&context, &listener.chain, listener).await;
+    let _permit = limits::admit(&route, &context)?;
+    let upstream_addr = upstream_address(&route.action, &context).await?;
+    log::debug!("Bedrock session {} -> {}", &client_addr, &upstream_addr);
+    let bind_addr = match upstream_addr {
+        SocketAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+        SocketAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+    };
+    let upstream = UdpSocket::bind(bind_addr).await?;
+    upstream.connect(upstream_addr).await?;
+    let session = Arc::new(Session {
+        upstream,
+        activity: Activity::new(),
+        upload: limits::Policer::new(route.limits.upload),
+        download: limits::Policer::new(route.limits.download),
+    });
+    let existing = match sessions.lock().await.entry(client_addr) {
+        Entry::Occupied(entry) => Some(entry.get().clone()),
+        Entry::Vacant(entry) => {
+            entry.insert(session.clone());
+            None
+        }
+    };
+    if let Some(existing) = existing {
+        existing.upstream.send(&packet).await?;
+        return Ok(());
+    }
+    let idle = route.timeouts.idle.unwrap_or(DEFAULT_SESSION_IDLE);
+    let result = async {
+        session.upstream.send(&packet).await?;
+        relay_upstream(&socket, &session, client_addr, idle).await
+    }
+    .await;
+    sessions.lock().await.remove(&client_addr);
+    log::debug!("Bedrock session closed: {}", &client_addr);
+    result
+}
+
+// Bedrock carries no host name, so the destination is the listener's
+// `fallback`, which is trusted like any other address in the configuration.
+// Without one the host is left empty: the configuration is only accepted when
+// the listener's chain forwards every session to an address of its own.
+fn session_context(listener: &args::Listener, client_addr: SocketAddr) -> anyhow::Result<Context> {
+    let (host, port) = match &listener.fallback {
+        Some(fallback) => {
+            let (host, port) = chain::split_address(fallback)?;
+            (host.to_owned(), port)
+        }
+        None => (String::new(), listener.port.unwrap_or(listener.addr.port())),
+    };
+    Ok(Context {
+        host,
+        port,
+        client: Some(client_addr),
+        local: Some(listener.addr),
+        trusted: true,
+        ..Default::default()
+    })
+}
+
+async fn relay_upstream(
+    socket: &UdpSocket,
+    session: &Session,
+    client_addr: SocketAddr,
+    idle: Duration,
+) -> anyhow::Result<()> {
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    loop {
+        tokio::select! {
+            received = session.upstream.recv(&mut buf) => {
+                let length = received?;
+                session.activity.touch();
+                if !session.download.allow(length) {
+                    continue;
+                }
+                socket.send_to(&buf[..length], client_addr).await?;
+            }
+            _ = tokio::time::sleep_until(session.activity.last() + idle) => {
+                if session.activity.last().elapsed() >= idle {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+// Sessions never carry a destination of their own: both the fallback and
+// `Forward` addresses come from the configuration, so neither is guarded.
+async fn upstream_address(action: &ChainAction, context: &Context) -> anyhow::Result<SocketAddr> {
+    match action {
+        ChainAction::DirectConnect { .. } if context.host.is_empty() => Err(anyhow::anyhow!(
+            "no destination for Bedrock session; set a fallback or forward it to an address"
+        )),
+        ChainAction::DirectConnect { family } => {
+            resolve(&context.host, context.port, *family).await
+        }
+        ChainAction::Forward { address, family } => {
+            let (host, port) = chain::split_address(address)?;
+            resolve(host, port, *family).await
+        }
+        ChainAction::Drop => Err(anyhow::anyhow!("drop")),
+        action => Err(anyhow::anyhow!(
+            "action {:?} is not supported for Bedrock sessions",
+            action
+        )),
+    }
+}
+
+async fn resolve(
+    host: &str,
+    port: u16,
+    family: Option<AddressFamily>,
+) -> anyhow::Result<SocketAddr> {
+    let resolver = dns::get_resolver().await;
+    let family = family.unwrap_or(resolver.family());
+    let address = resolver.lookup(host, family).await?.into_iter().next();
+    address
+        .map(|address| SocketAddr::new(address, port))
+        .with_context(|| format!("no addresses found for \"{}\"", host))
+}
+
+#[test]
+fn starts_session_test() {
+    let mut ping = vec![UNCONNECTED_PING];
+    ping.extend_from_slice(&[0; 8]);
+    ping.extend_from_slice(&OFFLINE_MESSAGE_MAGIC);
+    ping.extend_from_slice(&[0; 8]);
+    assert!(starts_session(&ping));
+
+    let mut request = vec![OPEN_CONNECTION_REQUEST_1];
+    request.extend_from_slice(&OFFLINE_MESSAGE_MAGIC);
+    request.push(11);
+    assert!(starts_session(&request));
+
+    request[5] = 0;
+    assert!(!starts_session(&request));
+    assert!(!starts_session(&[OPEN_CONNECTION_REQUEST_1, 0x00]));
+    assert!(!starts_session(&[0x84, 0x00, 0x00, 0x00]));
+}
+
+#[tokio::test]
+async fn no_fallback_test() {
+    let listener: args::Listener = "kind=bedrock,addr=0.0.0.0:19132,chain=bedrock"
+        .parse()
+        .unwrap();
+    let context = session_context(&listener, "192.0.2.1:50000".parse().unwrap()).unwrap();
+    assert!(context.host.is_empty());
+    let direct = ChainAction::DirectConnect { family: None };
+    assert!(upstream_address(&direct, &context).await.is_err());
+    let forward = ChainAction::Forward {
+        address: String::from("198.51.100.7:19133"),
+        family: None,
+    };
+    assert_eq!(
+        upstream_address(&forward, &context).await.unwrap(),
+        "198.51.100.7:19133".parse().unwrap()
+    );
+
+    let listener: args::Listener = "kind=bedrock,chain=bedrock,fallback=play.example.com:19132"
+        .parse()
+        .unwrap();
+    let context = session_context(&listener, "192.0.2.1:50000".parse().unwrap()).unwrap();
+    assert_eq!(context.host, "play.example.com");
+    assert_eq!(context.port, 19132);
+}
+
+// The fallback is written by the operator, so an internal address connects
+// without `allow_internal`.
+#[tokio::test]
+async fn internal_fallback_test() {
+    let listener: args::Listener = "kind=bedrock,chain=bedrock,fallback=192.168.1.20:19133"
+        .parse()
+        .unwrap();
+    let context = session_context(&listener, "192.0.2.1:50000".parse().unwrap()).unwrap();
+    assert!(context.trusted);
+    let direct = ChainAction::DirectConnect { family: None };
+    assert_eq!(
+        upstream_address(&direct, &context).await.unwrap(),
+        "192.168.1.20:19133".parse().unwrap()
+    );
+}
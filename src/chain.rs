@@ -1,38 +1,270 @@
-use anyhow::Result;
-use fast_socks5::client::Socks5Stream;
-use tokio::net::TcpStream;
+use std::net::{IpAddr, SocketAddr};
+
+use anyhow::{Context as _, Result};
+use fast_socks5::{client::Socks5Stream, util::target_addr::ToTargetAddr, AuthenticationMethod};
+use tokio::{io::AsyncWriteExt, net::TcpStream};
 use wildmatch::WildMatch;
 
-use crate::config::{self, ChainAction, ChainFilter, ChainRule, Credentials};
+use crate::{
+    args,
+    client_hello::ClientHello,
+    config::{
+        self, AddressFamily, ChainAction, ChainFilter, ChainRule, Credentials, DnsMode,
+        DomainStrategy, HttpForwarding, Limits, Minecraft, ProxyProtocol, Timeouts,
+    },
+    dns, geoip, happy_eyeballs, limits, proxy_protocol,
+    stream::BoxedStream,
+    timeout::{self, Phase},
+    tls,
+};
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct Context {
     pub host: String,
     pub port: u16,
-    pub address: String,
+    pub tls: Option<ClientHello>,
+    pub player: Option<String>,
+    pub user: Option<String>,
+    pub client: Option<SocketAddr>,
+    pub local: Option<SocketAddr>,
+    // Set when the destination is the listener's `fallback` rather than one
+    // the client asked for, so it is trusted like rule addresses.
+    pub trusted: bool,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Route {
+    pub action: ChainAction,
+    pub timeouts: Timeouts,
+    pub minecraft: Minecraft,
+    pub proxy_protocol: Option<ProxyProtocol>,
+    pub http: HttpForwarding,
+    pub allow_internal: bool,
+    pub limits: Limits,
+    pub limit_owners: limits::Owners,
+}
+
+impl Route {
+    pub async fn connect(&self, context: &Context) -> Result<BoxedStream> {
+        log::debug!("connect with route {:?}", self);
+        let allow_internal = self.allow_internal || context.trusted;
+        let mut stream = connect(&self.action, context, &self.timeouts, allow_internal).await?;
+        if let Some(version) = self.proxy_protocol {
+            match (context.client, context.local) {
+                (Some(client), Some(local)) => {
+                    let header = proxy_protocol::header(version, client, local);
+                    stream.write_all(&header).await?;
+                }
+                _ => log::warn!("no client address to send in PROXY protocol header"),
+            }
+        }
+        Ok(stream)
+    }
+}
+
+#[async_recursion::async_recursion]
+async fn connect(
+    action: &ChainAction,
+    context: &Context,
+    timeouts: &Timeouts,
+    allow_internal: bool,
+) -> Result<BoxedStream> {
+    let stream: BoxedStream = match action {
+        ChainAction::DirectConnect { family } => {
+            let stream = timeout::run(
+                Phase::Connect,
+                timeouts.connect,
+                direct_connect(&context.host, context.port, *family, !allow_internal),
+            )
+            .await?;
+            Box::new(stream)
+        }
+        ChainAction::Socks5Proxy {
+            credentials,
+            address,
+            dns,
+            tls,
+        } => {
+            let (host, port) = split_address(address)?;
+            let socket = timeout::run(
+                Phase::Connect,
+                timeouts.connect,
+                direct_connect(host, port, None, false),
+            )
+            .await?;
+            let socket: BoxedStream = match tls {
+                Some(tls) => Box::new(
+                    timeout::run(
+                        Phase::Handshake,
+                        timeouts.handshake,
+                        tls::connect(socket, host, tls),
+                    )
+                    .await?,
+                ),
+                None => Box::new(socket),
+            };
+            timeout::run(
+                Phase::Socks,
+                timeouts.socks,
+                socks5_connect(socket, address, context, credentials, *dns),
+            )
+            .await?
+        }
+        ChainAction::Forward { address, family } => {
+            let (host, port) = split_address(address)?;
+            let stream = timeout::run(
+                Phase::Connect,
+                timeouts.connect,
+                direct_connect(host, port, *family, false),
+            )
+            .await?;
+            Box::new(stream)
+        }
+        ChainAction::TlsWrap { inner, tls } => {
+            let server_name = match inner.as_ref() {
+                ChainAction::Forward { address, .. } => split_address(address)?.0,
+                _ => &context.host,
+            };
+            let stream = connect(inner, context, timeouts, allow_internal).await?;
+            let stream = timeout::run(
+                Phase::Handshake,
+                timeouts.handshake,
+                tls::connect(stream, server_name, tls),
+            )
+            .await?;
+            Box::new(stream)
+        }
+        ChainAction::GotoChain { chain } => {
+            return Err(anyhow::anyhow!("unresolved chain \"{}\"", chain))
+        }
+        ChainAction::Drop => return Err(anyhow::anyhow!("drop")),
+    };
+    Ok(stream)
 }
 
-pub async fn connect(context: Context, start: String) -> Result<TcpStream, anyhow::Error> {
-    log::debug!("resolve proxy stream for context: {:?}", &context);
+// Rules take precedence over the listener's timeouts and limits, which in
+// turn take precedence over the global timeouts.
+pub async fn route(context: &Context, start: &str, listener: &args::Listener) -> Route {
+    log::debug!("resolve route for context: {:?}", context);
     let config = config::get_current_config().await;
-    resolve(&config, &context, &start).await
+    let mut route = resolve(&config, context, start, Route::default()).await;
+    route.timeouts = route.timeouts.or(listener.timeouts).or(config.timeouts);
+    route.limits = route.limits.or(listener.limits);
+    let owner = format!("listener {}", listener.addr);
+    route.limit_owners = route
+        .limit_owners
+        .or(limits::Owners::new(&listener.limits, &owner));
+    route
 }
 
+// `route` carries the options collected from the rules matched so far; rules
+// reached through `GotoChain` take precedence over the ones leading there.
 #[async_recursion::async_recursion]
-async fn resolve(config: &config::Config, context: &Context, start: &str) -> Result<TcpStream> {
+async fn resolve(config: &config::Config, context: &Context, start: &str, route: Route) -> Route {
     match config.chains.get(start) {
-        Some(chain) => resolve_chain(config, context, chain).await,
-        None => direct_connect(&context.address).await,
+        Some(chain) => resolve_chain(config, context, start, chain, route).await,
+        None => route,
     }
 }
 
 async fn resolve_chain(
     config: &config::Config,
     context: &Context,
-    chain: &Vec<ChainRule>,
-) -> Result<TcpStream> {
-    for rule in chain {
+    name: &str,
+    chain: &[ChainRule],
+    route: Route,
+) -> Route {
+    let mut addresses = Addresses::new(&context.host);
+    let rule = match (&addresses, config.routing.domain_strategy) {
+        (Addresses::Unresolved, DomainStrategy::IpIfNonMatch) => {
+            match find_rule(config, context, chain, &mut addresses, true).await {
+                Some(rule) => Some(rule),
+                None => {
+                    addresses.resolve(&context.host).await;
+                    find_rule(config, context, chain, &mut addresses, false).await
+                }
+            }
+        }
+        _ => find_rule(config, context, chain, &mut addresses, false).await,
+    };
+    match rule {
+        Some((index, rule)) => {
+            let owner = format!("chain \"{}\" rule {}", name, index);
+            let route = Route {
+                action: rule.action.clone(),
+                timeouts: rule.timeouts.or(route.timeouts),
+                minecraft: rule.minecraft.clone().or(route.minecraft),
+                proxy_protocol: rule.proxy_protocol.or(route.proxy_protocol),
+                http: rule.http.or(route.http),
+                allow_internal: rule.allow_internal || route.allow_internal,
+                limits: rule.limits.or(route.limits),
+                limit_owners: limits::Owners::new(&rule.limits, &owner).or(route.limit_owners),
+            };
+            match &rule.action {
+                ChainAction::GotoChain { chain } => {
+                    resolve(
+                        config,
+                        context,
+                        chain,
+                        Route {
+                            action: ChainAction::default(),
+                            ..route
+                        },
+                    )
+                    .await
+                }
+                _ => route,
+            }
+        }
+        None => route,
+    }
+}
+
+enum Addresses {
+    Unresolved,
+    Resolved(Vec<IpAddr>),
+}
+
+impl Addresses {
+    fn new(host: &str) -> Self {
+        match host.parse() {
+            Ok(address) => Addresses::Resolved(vec![address]),
+            Err(_) => Addresses::Unresolved,
+        }
+    }
+
+    fn known(&self) -> &[IpAddr] {
+        match self {
+            Addresses::Unresolved => &[],
+            Addresses::Resolved(addresses) => addresses,
+        }
+    }
+
+    async fn resolve(&mut self, host: &str) -> &[IpAddr] {
+        if let Addresses::Unresolved = self {
+            let addresses = dns::lookup(host).await.unwrap_or_else(|error| {
+                log::debug!("failed to resolve \"{}\" for routing: {}", host, error);
+                Vec::new()
+            });
+            *self = Addresses::Resolved(addresses);
+        }
+        self.known()
+    }
+}
+
+// With `domain_only` set, IP range filters are skipped and the search stops at
+// the first catch-all rule, so that after resolution the rules are tried again in
+// order and a catch-all still wins over any rule placed after it.
+async fn find_rule<'a>(
+    config: &config::Config,
+    context: &Context,
+    chain: &'a [ChainRule],
+    addresses: &mut Addresses,
+    domain_only: bool,
+) -> Option<(usize, &'a ChainRule)> {
+    for (index, rule) in chain.iter().enumerate() {
         let matches = match &rule.filter {
+            ChainFilter::Anything if domain_only => break,
             ChainFilter::Anything => true,
             ChainFilter::DomainPool { pool } => match config.stash.domain_pools.get(pool) {
                 Some(pool) => pool.0.contains(&context.host),
@@ -42,66 +274,327 @@ async fn resolve_chain(
                 }
             },
             ChainFilter::DomainWildcard { wildcard } => {
-                WildMatch::new(&wildcard).matches(&context.host)
+                WildMatch::new(wildcard).matches(&context.host)
+            }
+            ChainFilter::IpCidr { cidr } => addresses
+                .known()
+                .iter()
+                .any(|address| cidr.contains(address)),
+            ChainFilter::IpPool { pool } => match config.stash.ip_pools.get(pool) {
+                Some(pool) => addresses
+                    .known()
+                    .iter()
+                    .any(|address| pool.0.iter().any(|cidr| cidr.contains(address))),
+                None => {
+                    log::warn!("IP pool \"{}\" not found in configuration", pool);
+                    false
+                }
+            },
+            ChainFilter::Alpn { protocols } => context
+                .tls
+                .as_ref()
+                .is_some_and(|tls| tls.alpn.iter().any(|protocol| protocols.contains(protocol))),
+            ChainFilter::TlsVersion { min, max } => context.tls.as_ref().is_some_and(|tls| {
+                let version = tls.max_version();
+                min.is_none_or(|min| version >= min.wire())
+                    && max.is_none_or(|max| version <= max.wire())
+            }),
+            ChainFilter::Ja3 { fingerprints } => context
+                .tls
+                .as_ref()
+                .is_some_and(|tls| fingerprints.contains(&tls.ja3_hash())),
+            ChainFilter::EncryptedClientHello { present } => context
+                .tls
+                .as_ref()
+                .is_some_and(|tls| tls.encrypted == *present),
+            ChainFilter::PlayerName { name } => context
+                .player
+                .as_ref()
+                .is_some_and(|player| player.eq_ignore_ascii_case(name)),
+            ChainFilter::User { name } => context.user.as_ref() == Some(name),
+            ChainFilter::PlayerPool { pool } => match config.stash.player_pools.get(pool) {
+                Some(pool) => context.player.as_ref().is_some_and(|player| {
+                    pool.0.iter().any(|name| name.eq_ignore_ascii_case(player))
+                }),
+                None => {
+                    log::warn!("player pool \"{}\" not found in configuration", pool);
+                    false
+                }
+            },
+            // Country and ASN lookups need addresses, so the host is resolved
+            // on demand and kept for the following rules.
+            ChainFilter::GeoIp { countries } => {
+                let databases = geoip::get_databases().await;
+                addresses
+                    .resolve(&context.host)
+                    .await
+                    .iter()
+                    .any(|address| {
+                        databases.country(*address).is_some_and(|country| {
+                            countries.iter().any(|it| it.eq_ignore_ascii_case(&country))
+                        })
+                    })
+            }
+            ChainFilter::Asn { numbers } => {
+                let databases = geoip::get_databases().await;
+                addresses
+                    .resolve(&context.host)
+                    .await
+                    .iter()
+                    .any(|address| {
+                        databases
+                            .asn(*address)
+                            .is_some_and(|number| numbers.contains(&number))
+                    })
             }
         };
         if matches {
-            return match &rule.action {
-                ChainAction::DirectConnect => direct_connect(&context.address).await,
-                ChainAction::GotoChain { chain } => resolve(config, context, &chain).await,
-                ChainAction::Socks5Proxy {
-                    credentials,
-                    address,
-                } => socks5_connect(address, &context.host, context.port, credentials).await,
-                ChainAction::Forward { address } => direct_connect(&address).await,
-                ChainAction::Drop => Err(anyhow::anyhow!("drop")),
-            };
+            return Some((index, rule));
         }
     }
-    direct_connect(&context.address).await
+    None
 }
 
 async fn socks5_connect(
+    socket: BoxedStream,
     address: &str,
-    host: &str,
-    port: u16,
+    context: &Context,
     credentials: &Option<Credentials>,
-) -> Result<TcpStream> {
+    dns: DnsMode,
+) -> Result<BoxedStream> {
+    let host = &context.host;
+    let target_addr = match dns {
+        DnsMode::Remote => String::from(host),
+        DnsMode::Local => match dns::lookup(host).await?.first() {
+            Some(address) => address.to_string(),
+            None => return Err(anyhow::anyhow!("no addresses found for \"{}\"", host)),
+        },
+    };
+    let target_addr = (target_addr.as_str(), context.port).to_target_addr()?;
+    let auth = credentials
+        .as_ref()
+        .map(
+            |Credentials { username, password }| AuthenticationMethod::Password {
+                username: String::from(username),
+                password: String::from(&password.0),
+            },
+        );
     let config = fast_socks5::client::Config::default();
-    let target_addr = String::from(host);
-    let stream = match credentials {
-        Some(Credentials { username, password }) => {
-            Socks5Stream::connect_with_password(
-                address,
-                target_addr,
-                port,
-                String::from(username),
-                String::from(&password.0),
-                config,
-            )
-            .await
-        }
-        None => Socks5Stream::connect(address, target_addr, port, config).await,
-    }
-    .or_else(|error| {
+    let result = async {
+        let mut stream = Socks5Stream::use_stream(socket, auth, config).await?;
+        stream
+            .request(fast_socks5::Socks5Command::TCPConnect, target_addr)
+            .await?;
+        Ok::<_, fast_socks5::SocksError>(stream)
+    };
+    let stream = result.await.map_err(|error| {
         log::error!(
-            "failed to create a direct connection with {}: {}",
+            "failed to create a SOCKS5 connection through {}: {}",
             address,
             error
         );
-        Err(error)
+        error
     })?;
     Ok(stream.get_socket())
 }
 
-async fn direct_connect(address: &str) -> Result<TcpStream> {
-    let stream = TcpStream::connect(address).await.or_else(|error| {
-        log::error!(
-            "failed to create a direct connection with {}: {}",
-            address,
+// Only targets requested by clients are guarded; addresses written in the
+// configuration are trusted.
+async fn direct_connect(
+    host: &str,
+    port: u16,
+    family: Option<AddressFamily>,
+    guarded: bool,
+) -> Result<TcpStream> {
+    let family = match family {
+        Some(family) => family,
+        None => dns::get_resolver().await.family(),
+    };
+    happy_eyeballs::connect(host, port, family, guarded)
+        .await
+        .map_err(|error| {
+            log::error!(
+                "failed to create a direct connection with {}:{}: {}",
+                host,
+                port,
+                error
+            );
             error
-        );
-        Err(error)
-    })?;
-    Ok(stream)
+        })
+}
+
+pub fn split_address(address: &str) -> Result<(&str, u16)> {
+    let (host, port) = address
+        .rsplit_once(':')
+        .with_context(|| format!("no port in address \"{}\"", address))?;
+    let port = port
+        .parse()
+        .with_context(|| format!("bad port in address \"{}\"", address))?;
+    Ok((host.trim_start_matches('[').trim_end_matches(']'), port))
+}
+
+#[test]
+fn split_address_test() {
+    assert_eq!(
+        split_address("example.com:25565").unwrap(),
+        ("example.com", 25565)
+    );
+    assert_eq!(split_address("[::1]:443").unwrap(), ("::1", 443));
+    assert!(split_address("example.com").is_err());
+}
+
+#[tokio::test]
+async fn find_rule_ip_if_non_match_test() {
+    let chain: Vec<ChainRule> = serde_json::from_str(
+        r#"[
+            {"filter":{"IpCidr":{"cidr":"192.168.0.0/16"}},"action":"DirectConnect"},
+            {"filter":"Anything","action":"Drop"}
+        ]"#,
+    )
+    .unwrap();
+    let config = config::Config::default();
+    let context = Context {
+        host: String::from("nas.lan"),
+        port: 80,
+        ..Default::default()
+    };
+    let mut addresses = Addresses::Unresolved;
+    let rule = find_rule(&config, &context, &chain, &mut addresses, true).await;
+    assert!(rule.is_none());
+    let mut addresses = Addresses::Resolved(vec!["192.168.1.5".parse().unwrap()]);
+    let rule = find_rule(&config, &context, &chain, &mut addresses, false).await;
+    assert!(matches!(
+        rule.unwrap().1.action,
+        ChainAction::DirectConnect { .. }
+    ));
+    let mut addresses = Addresses::Resolved(vec!["10.0.0.1".parse().unwrap()]);
+    let rule = find_rule(&config, &context, &chain, &mut addresses, false).await;
+    assert!(matches!(rule.unwrap().1.action, ChainAction::Drop));
+
+    // A domain rule placed after the catch-all never wins over it.
+    let chain: Vec<ChainRule> = serde_json::from_str(
+        r#"[
+            {"filter":"Anything","action":"Drop"},
+            {"filter":{"DomainWildcard":{"wildcard":"*.lan"}},"action":"DirectConnect"}
+        ]"#,
+    )
+    .unwrap();
+    let mut addresses = Addresses::Unresolved;
+    let rule = find_rule(&config, &context, &chain, &mut addresses, true).await;
+    assert!(rule.is_none());
+    let mut addresses = Addresses::Resolved(vec!["192.168.1.5".parse().unwrap()]);
+    let rule = find_rule(&config, &context, &chain, &mut addresses, false).await;
+    assert!(matches!(rule.unwrap().1.action, ChainAction::Drop));
+}
+
+// GeoIp and Asn filters resolve the host themselves, under either strategy.
+#[tokio::test]
+async fn find_rule_geoip_test() {
+    let directory = std::env::temp_dir();
+    let country = directory.join(format!("rkp-chain-country-{}.mmdb", std::process::id()));
+    let asn = directory.join(format!("rkp-chain-asn-{}.mmdb", std::process::id()));
+    std::fs::write(
+        &country,
+        geoip::test_database(
+            "GeoLite2-Country",
+            &[("192.0.2.0/24", geoip::test_country("DE"))],
+        ),
+    )
+    .unwrap();
+    std::fs::write(
+        &asn,
+        geoip::test_database(
+            "GeoLite2-ASN",
+            &[("198.51.100.0/24", geoip::test_asn(64500))],
+        ),
+    )
+    .unwrap();
+    geoip::install(&config::GeoIp {
+        country_database: Some(country.clone()),
+        asn_database: Some(asn.clone()),
+    })
+    .await;
+    std::fs::remove_file(&country).unwrap();
+    std::fs::remove_file(&asn).unwrap();
+
+    let chain: Vec<ChainRule> = serde_json::from_str(
+        r#"[
+            {"filter":{"GeoIp":{"countries":["de"]}},"action":"DirectConnect"},
+            {"filter":{"Asn":{"numbers":[64500]}},"action":"Drop"}
+        ]"#,
+    )
+    .unwrap();
+    // The names resolve from the hosts map, without touching the network.
+    dns::install(&config::Dns {
+        hosts: std::collections::HashMap::from([
+            (
+                String::from("de.example"),
+                vec!["192.0.2.7".parse().unwrap()],
+            ),
+            (
+                String::from("as.example"),
+                vec!["198.51.100.1".parse().unwrap()],
+            ),
+            (
+                String::from("other.example"),
+                vec!["203.0.113.1".parse().unwrap()],
+            ),
+        ]),
+        ..Default::default()
+    })
+    .await;
+    let config = config::Config::default();
+    let context = |host: &str| Context {
+        host: String::from(host),
+        port: 443,
+        ..Default::default()
+    };
+    for domain_only in [true, false] {
+        let mut addresses = Addresses::Unresolved;
+        let rule = find_rule(
+            &config,
+            &context("de.example"),
+            &chain,
+            &mut addresses,
+            domain_only,
+        )
+        .await;
+        assert!(matches!(
+            rule.unwrap().1.action,
+            ChainAction::DirectConnect { .. }
+        ));
+        assert_eq!(addresses.known(), ["192.0.2.7".parse::<IpAddr>().unwrap()]);
+
+        let mut addresses = Addresses::Unresolved;
+        let rule = find_rule(
+            &config,
+            &context("as.example"),
+            &chain,
+            &mut addresses,
+            domain_only,
+        )
+        .await;
+        assert!(matches!(rule.unwrap().1.action, ChainAction::Drop));
+
+        let mut addresses = Addresses::Unresolved;
+        assert!(find_rule(
+            &config,
+            &context("other.example"),
+            &chain,
+            &mut addresses,
+            domain_only
+        )
+        .await
+        .is_none());
+    }
+    let mut addresses = Addresses::Resolved(vec!["198.51.100.1".parse().unwrap()]);
+    let rule = find_rule(
+        &config,
+        &context("de.example"),
+        &chain,
+        &mut addresses,
+        false,
+    )
+    .await;
+    assert!(matches!(rule.unwrap().1.action, ChainAction::Drop));
 }
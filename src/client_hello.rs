@@ -0,0 +1,412 @@
+use std::{fmt::Display, io, str::from_utf8};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use md5::{Digest, Md5};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const CONTENT_TYPE_HANDSHAKE: u8 = 22;
+const HANDSHAKE_TYPE_CLIENT_HELLO: u8 = 1;
+const EXTENSION_SERVER_NAME: u16 = 0;
+const EXTENSION_SERVER_NAME_TYPE_HOSTNAME: u8 = 0;
+const EXTENSION_SUPPORTED_GROUPS: u16 = 10;
+const EXTENSION_EC_POINT_FORMATS: u16 = 11;
+const EXTENSION_ALPN: u16 = 16;
+const EXTENSION_SUPPORTED_VERSIONS: u16 = 43;
+const EXTENSION_ENCRYPTED_CLIENT_HELLO: u16 = 0xfe0d;
+const EXTENSION_ENCRYPTED_SERVER_NAME: u16 = 0xffce;
+
+const RECORD_HEADER_LENGTH: usize = 5;
+const MAX_RECORD_LENGTH: usize = 1 << 14;
+const MAX_CLIENT_HELLO_LENGTH: usize = 1 << 16;
+
+#[derive(Debug)]
+pub enum ClientHelloError {
+    Io(io::Error),
+    ContentType(u8),
+    HandshakeType(u8),
+    RecordLength(usize),
+    MessageLength(usize),
+    Truncated(&'static str),
+    ServerName,
+}
+
+impl Display for ClientHelloError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientHelloError::Io(error) => write!(f, "failed to read ClientHello: {}", error),
+            ClientHelloError::ContentType(value) => write!(
+                f,
+                "wrong TLS record content type ({} expected, got {})",
+                CONTENT_TYPE_HANDSHAKE, value
+            ),
+            ClientHelloError::HandshakeType(value) => write!(
+                f,
+                "wrong TLS handshake type ({} expected, got {})",
+                HANDSHAKE_TYPE_CLIENT_HELLO, value
+            ),
+            ClientHelloError::RecordLength(value) => {
+                write!(f, "bad TLS record length {}", value)
+            }
+            ClientHelloError::MessageLength(value) => {
+                write!(f, "ClientHello is too large ({} bytes)", value)
+            }
+            ClientHelloError::Truncated(field) => {
+                write!(f, "ClientHello is truncated at field \"{}\"", field)
+            }
+            ClientHelloError::ServerName => write!(f, "server_name is not a valid hostname"),
+        }
+    }
+}
+
+impl std::error::Error for ClientHelloError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ClientHelloError::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ClientHelloError {
+    fn from(error: io::Error) -> Self {
+        ClientHelloError::Io(error)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ClientHello {
+    pub server_name: Option<String>,
+    pub legacy_version: u16,
+    pub cipher_suites: Vec<u16>,
+    pub extensions: Vec<u16>,
+    pub alpn: Vec<String>,
+    pub supported_versions: Vec<u16>,
+    pub supported_groups: Vec<u16>,
+    pub ec_point_formats: Vec<u8>,
+    pub encrypted: bool,
+}
+
+impl ClientHello {
+    pub fn max_version(&self) -> u16 {
+        self.supported_versions
+            .iter()
+            .copied()
+            .max()
+            .unwrap_or(self.legacy_version)
+    }
+
+    pub fn ja3(&self) -> String {
+        fn join<T: Display>(values: &[T]) -> String {
+            let values: Vec<String> = values.iter().map(ToString::to_string).collect();
+            values.join("-")
+        }
+        format!(
+            "{},{},{},{},{}",
+            self.legacy_version,
+            join(&self.cipher_suites),
+            join(&self.extensions),
+            join(&self.supported_groups),
+            join(&self.ec_point_formats)
+        )
+    }
+
+    pub fn ja3_hash(&self) -> String {
+        Md5::digest(self.ja3().as_bytes())
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+}
+
+// RFC 8701
+fn is_grease(value: u16) -> bool {
+    value & 0x0f0f == 0x0a0a && value >> 8 == value & 0xff
+}
+
+// Reads TLS records until the whole ClientHello handshake message is
+// assembled. All bytes read from the stream are returned to be replayed.
+pub async fn read<R>(stream: &mut R) -> Result<(ClientHello, Bytes), ClientHelloError>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut raw = BytesMut::new();
+    let mut message = BytesMut::new();
+    loop {
+        let mut header = [0u8; RECORD_HEADER_LENGTH];
+        stream.read_exact(&mut header).await?;
+        if header[0] != CONTENT_TYPE_HANDSHAKE {
+            return Err(ClientHelloError::ContentType(header[0]));
+        }
+        let length = u16::from_be_bytes([header[3], header[4]]) as usize;
+        if length == 0 || length > MAX_RECORD_LENGTH {
+            return Err(ClientHelloError::RecordLength(length));
+        }
+        let offset = raw.len();
+        raw.put_slice(&header);
+        raw.resize(offset + RECORD_HEADER_LENGTH + length, 0);
+        stream
+            .read_exact(&mut raw[offset + RECORD_HEADER_LENGTH..])
+            .await?;
+        message.put_slice(&raw[offset + RECORD_HEADER_LENGTH..]);
+
+        if message.len() >= 4 {
+            if message[0] != HANDSHAKE_TYPE_CLIENT_HELLO {
+                return Err(ClientHelloError::HandshakeType(message[0]));
+            }
+            let length = u32::from_be_bytes([0, message[1], message[2], message[3]]) as usize;
+            if length > MAX_CLIENT_HELLO_LENGTH {
+                return Err(ClientHelloError::MessageLength(length));
+            }
+            if message.len() >= 4 + length {
+                let client_hello = parse(&message[4..4 + length])?;
+                return Ok((client_hello, raw.freeze()));
+            }
+        }
+    }
+}
+
+pub fn parse(body: &[u8]) -> Result<ClientHello, ClientHelloError> {
+    let mut reader = Reader(body);
+    let mut client_hello = ClientHello {
+        legacy_version: reader.u16("legacy_version")?,
+        ..Default::default()
+    };
+    reader.skip(32, "random")?;
+    reader.vec8("legacy_session_id")?;
+    let mut cipher_suites = Reader(reader.vec16("cipher_suites")?);
+    client_hello.cipher_suites = cipher_suites.u16_list("cipher_suites")?;
+    reader.vec8("legacy_compression_methods")?;
+    if reader.is_empty() {
+        return Ok(client_hello);
+    }
+    let mut extensions = Reader(reader.vec16("extensions")?);
+    while !extensions.is_empty() {
+        let extension_type = extensions.u16("extension_type")?;
+        let mut data = Reader(extensions.vec16("extension_data")?);
+        if !is_grease(extension_type) {
+            client_hello.extensions.push(extension_type);
+        }
+        match extension_type {
+            EXTENSION_SERVER_NAME => client_hello.server_name = parse_server_name(data)?,
+            EXTENSION_SUPPORTED_GROUPS => {
+                let mut groups = Reader(data.vec16("supported_groups")?);
+                client_hello.supported_groups = groups.u16_list("supported_groups")?;
+            }
+            EXTENSION_EC_POINT_FORMATS => {
+                client_hello.ec_point_formats = data.vec8("ec_point_formats")?.to_vec();
+            }
+            EXTENSION_ALPN => {
+                let mut protocols = Reader(data.vec16("protocol_name_list")?);
+                while !protocols.is_empty() {
+                    let protocol = protocols.vec8("protocol_name")?;
+                    client_hello
+                        .alpn
+                        .push(String::from_utf8_lossy(protocol).into_owned());
+                }
+            }
+            EXTENSION_SUPPORTED_VERSIONS => {
+                let mut versions = Reader(data.vec8("supported_versions")?);
+                client_hello.supported_versions = versions.u16_list("supported_versions")?;
+            }
+            EXTENSION_ENCRYPTED_CLIENT_HELLO | EXTENSION_ENCRYPTED_SERVER_NAME => {
+                client_hello.encrypted = true;
+            }
+            _ => {}
+        }
+    }
+    Ok(client_hello)
+}
+
+fn parse_server_name(mut data: Reader) -> Result<Option<String>, ClientHelloError> {
+    let mut list = Reader(data.vec16("server_name_list")?);
+    while !list.is_empty() {
+        let name_type = list.u8("name_type")?;
+        let name = list.vec16("host_name")?;
+        if name_type == EXTENSION_SERVER_NAME_TYPE_HOSTNAME {
+            let name = from_utf8(name).map_err(|_| ClientHelloError::ServerName)?;
+            return Ok(Some(name.to_owned()));
+        }
+    }
+    Ok(None)
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn take(&mut self, length: usize, field: &'static str) -> Result<&'a [u8], ClientHelloError> {
+        if self.0.len() < length {
+            return Err(ClientHelloError::Truncated(field));
+        }
+        let (value, rest) = self.0.split_at(length);
+        self.0 = rest;
+        Ok(value)
+    }
+
+    fn skip(&mut self, length: usize, field: &'static str) -> Result<(), ClientHelloError> {
+        self.take(length, field).map(|_| ())
+    }
+
+    fn u8(&mut self, field: &'static str) -> Result<u8, ClientHelloError> {
+        Ok(self.take(1, field)?[0])
+    }
+
+    fn u16(&mut self, field: &'static str) -> Result<u16, ClientHelloError> {
+        let bytes = self.take(2, field)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u16_list(&mut self, field: &'static str) -> Result<Vec<u16>, ClientHelloError> {
+        let mut values = Vec::with_capacity(self.0.len() / 2);
+        while !self.is_empty() {
+            let value = self.u16(field)?;
+            if !is_grease(value) {
+                values.push(value);
+            }
+        }
+        Ok(values)
+    }
+
+    fn vec8(&mut self, field: &'static str) -> Result<&'a [u8], ClientHelloError> {
+        let length = self.u8(field)?;
+        self.take(length.into(), field)
+    }
+
+    fn vec16(&mut self, field: &'static str) -> Result<&'a [u8], ClientHelloError> {
+        let length = self.u16(field)?;
+        self.take(length.into(), field)
+    }
+}
+
+#[cfg(test)]
+fn client_hello_body(server_name: &str, padding: usize) -> Vec<u8> {
+    let mut extensions = Vec::new();
+    let name = server_name.as_bytes();
+    extensions.put_u16(EXTENSION_SERVER_NAME);
+    extensions.put_u16(name.len() as u16 + 5);
+    extensions.put_u16(name.len() as u16 + 3);
+    extensions.put_u8(EXTENSION_SERVER_NAME_TYPE_HOSTNAME);
+    extensions.put_u16(name.len() as u16);
+    extensions.put_slice(name);
+    extensions.put_slice(&[0x0a, 0x0a, 0, 0]);
+    extensions.put_u16(EXTENSION_ALPN);
+    extensions.put_u16(14);
+    extensions.put_u16(12);
+    extensions.put_u8(2);
+    extensions.put_slice(b"h2");
+    extensions.put_u8(8);
+    extensions.put_slice(b"http/1.1");
+    extensions.put_u16(EXTENSION_SUPPORTED_VERSIONS);
+    extensions.put_u16(7);
+    extensions.put_u8(6);
+    extensions.put_slice(&[0x3a, 0x3a, 0x03, 0x04, 0x03, 0x03]);
+    extensions.put_u16(EXTENSION_SUPPORTED_GROUPS);
+    extensions.put_u16(4);
+    extensions.put_u16(2);
+    extensions.put_u16(0x001d);
+    extensions.put_u16(EXTENSION_EC_POINT_FORMATS);
+    extensions.put_u16(2);
+    extensions.put_u8(1);
+    extensions.put_u8(0);
+    // padding extension standing in for a large key share
+    extensions.put_u16(21);
+    extensions.put_u16(padding as u16);
+    extensions.put_bytes(0, padding);
+
+    let mut body = Vec::new();
+    body.put_u16(0x0303);
+    body.put_bytes(7, 32);
+    body.put_u8(0);
+    body.put_u16(2);
+    body.put_u16(0x1301);
+    body.put_u8(1);
+    body.put_u8(0);
+    body.put_u16(extensions.len() as u16);
+    body.put_slice(&extensions);
+    body
+}
+
+#[cfg(test)]
+fn records(body: &[u8], fragment: usize) -> Vec<u8> {
+    let mut message = Vec::new();
+    message.put_u8(HANDSHAKE_TYPE_CLIENT_HELLO);
+    message.put_slice(&(body.len() as u32).to_be_bytes()[1..]);
+    message.put_slice(body);
+    let mut records = Vec::new();
+    for chunk in message.chunks(fragment) {
+        records.put_u8(CONTENT_TYPE_HANDSHAKE);
+        records.put_u16(0x0301);
+        records.put_u16(chunk.len() as u16);
+        records.put_slice(chunk);
+    }
+    records
+}
+
+#[tokio::test]
+async fn single_record_test() {
+    let input = records(&client_hello_body("example.com", 16), MAX_RECORD_LENGTH);
+    let (client_hello, raw) = read(&mut &input[..]).await.unwrap();
+    assert_eq!(client_hello.server_name.as_deref(), Some("example.com"));
+    assert_eq!(&raw[..], &input[..]);
+}
+
+#[tokio::test]
+async fn fragmented_records_test() {
+    let input = records(&client_hello_body("example.com", 2000), 512);
+    let (mut client, mut server) = tokio::io::duplex(64);
+    let writer = tokio::spawn({
+        let input = input.clone();
+        async move {
+            use tokio::io::AsyncWriteExt;
+            for chunk in input.chunks(7) {
+                client.write_all(chunk).await.unwrap();
+            }
+            client
+        }
+    });
+    let (client_hello, raw) = read(&mut server).await.unwrap();
+    writer.await.unwrap();
+    assert_eq!(client_hello.server_name.as_deref(), Some("example.com"));
+    assert_eq!(&raw[..], &input[..]);
+}
+
+#[test]
+fn metadata_test() {
+    let client_hello = parse(&client_hello_body("example.com", 16)).unwrap();
+    assert_eq!(client_hello.alpn, vec!["h2", "http/1.1"]);
+    assert_eq!(client_hello.supported_versions, vec![0x0304, 0x0303]);
+    assert_eq!(client_hello.max_version(), 0x0304);
+    assert!(!client_hello.encrypted);
+    assert_eq!(client_hello.ja3(), "771,4865,0-16-43-10-11-21,29,0");
+    assert_eq!(client_hello.ja3_hash().len(), 32);
+}
+
+#[test]
+fn truncated_test() {
+    let body = client_hello_body("example.com", 16);
+    for length in 0..body.len() {
+        if let Ok(client_hello) = parse(&body[..length]) {
+            assert!(client_hello.server_name.is_none());
+        }
+    }
+}
+
+#[test]
+fn invalid_server_name_test() {
+    let mut body = client_hello_body("example.com", 0);
+    let position = body.windows(7).position(|it| it == b"example").unwrap();
+    body[position] = 0xff;
+    assert!(matches!(parse(&body), Err(ClientHelloError::ServerName)));
+}
+
+#[tokio::test]
+async fn wrong_content_type_test() {
+    let input = [23u8, 3, 3, 0, 1, 0];
+    assert!(matches!(
+        read(&mut &input[..]).await,
+        Err(ClientHelloError::ContentType(23))
+    ));
+}
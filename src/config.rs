@@ -1,13 +1,18 @@
 use std::{
     collections::{BTreeSet, HashMap},
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
     sync::Arc,
+    time::Duration,
 };
 
+use ipnet::IpNet;
 use serde::{Deserialize, Serialize};
 use tokio::{fs, sync::RwLock};
 use zeroize::{Zeroize, ZeroizeOnDrop};
 
-use crate::args;
+use crate::{args, dns, geoip, tls};
 
 #[derive(Debug, Serialize, Deserialize, Default, Clone)]
 pub struct Config {
@@ -15,12 +20,268 @@ pub struct Config {
     pub chains: HashMap<String, Vec<ChainRule>>,
     #[serde(default)]
     pub stash: Stash,
+    #[serde(default)]
+    pub dns: Dns,
+    #[serde(default)]
+    pub routing: Routing,
+    #[serde(default)]
+    pub geoip: GeoIp,
+    #[serde(default)]
+    pub timeouts: Timeouts,
+    #[serde(default)]
+    pub tls: Tls,
+    #[serde(default)]
+    pub http_pool: HttpPool,
+    #[serde(default)]
+    pub users: HashMap<String, User>,
+    #[serde(default)]
+    pub access: Access,
+}
+
+impl Config {
+    // Checks what serde cannot: pools referenced from the configuration or
+    // the listeners' arguments, and actions that cannot be nested.
+    pub fn validate(&self, listeners: &[args::Listener]) -> anyhow::Result<()> {
+        let ranges = listeners
+            .iter()
+            .flat_map(|listener| listener.allow.iter().chain(&listener.deny))
+            .chain(&self.access.deny);
+        for range in ranges {
+            if let ClientRange::Pool(pool) = range {
+                if !self.stash.ip_pools.contains_key(pool) {
+                    return Err(anyhow::anyhow!(
+                        "ip pool \"{}\" not found in configuration",
+                        pool
+                    ));
+                }
+            }
+        }
+        for (name, chain) in &self.chains {
+            for rule in chain {
+                check_tls_wrap(&rule.action)
+                    .map_err(|error| anyhow::anyhow!("in chain \"{}\": {}", name, error))?;
+            }
+        }
+        for listener in listeners {
+            if matches!(listener.kind, args::ListenerKind::Bedrock)
+                && listener.fallback.is_none()
+                && !self.forwards_everything(&listener.chain, &mut Vec::new())
+            {
+                return Err(anyhow::anyhow!(
+                    "Bedrock listener {} needs a fallback unless chain \"{}\" forwards every session",
+                    listener.addr,
+                    listener.chain
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // Bedrock sessions carry no destination, so without a fallback every
+    // session has to end in a rule naming its own upstream. Sessions no rule
+    // matches fall through to `DirectConnect`.
+    fn forwards_everything<'a>(&'a self, name: &'a str, visited: &mut Vec<&'a str>) -> bool {
+        if visited.contains(&name) {
+            return false;
+        }
+        visited.push(name);
+        let chain = match self.chains.get(name) {
+            Some(chain) => chain,
+            None => return false,
+        };
+        for rule in chain {
+            let forwards = match &rule.action {
+                ChainAction::Forward { .. } | ChainAction::Drop => true,
+                ChainAction::GotoChain { chain } => self.forwards_everything(chain, visited),
+                _ => false,
+            };
+            if !forwards {
+                return false;
+            }
+            if let ChainFilter::Anything = rule.filter {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+// The stream TlsWrap wraps is opened by the inner action directly, after the
+// route has been resolved, so there is no chain lookup left to follow.
+fn check_tls_wrap(action: &ChainAction) -> anyhow::Result<()> {
+    match action {
+        ChainAction::TlsWrap { inner, .. } => match inner.as_ref() {
+            ChainAction::GotoChain { chain } => Err(anyhow::anyhow!(
+                "TlsWrap cannot wrap GotoChain \"{}\"",
+                chain
+            )),
+            inner => check_tls_wrap(inner),
+        },
+        _ => Ok(()),
+    }
+}
+
+// Clients refused by every listener, on top of the listeners' own lists.
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+pub struct Access {
+    #[serde(default)]
+    pub deny: Vec<ClientRange>,
+}
+
+// Client addresses given as a CIDR, a single address or `pool:` followed by
+// the name of an IP pool in the stash.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(try_from = "String", into = "String")]
+pub enum ClientRange {
+    Net(IpNet),
+    Pool(String),
+}
+
+impl FromStr for ClientRange {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(pool) = s.strip_prefix("pool:") {
+            return Ok(ClientRange::Pool(pool.to_owned()));
+        }
+        match (s.parse::<IpNet>(), s.parse::<IpAddr>()) {
+            (Ok(net), _) => Ok(ClientRange::Net(net)),
+            (_, Ok(address)) => Ok(ClientRange::Net(address.into())),
+            _ => Err(anyhow::anyhow!(
+                "\"{}\" is neither an address, a CIDR nor a \"pool:\" reference",
+                s
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for ClientRange {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<ClientRange> for String {
+    fn from(range: ClientRange) -> Self {
+        match range {
+            ClientRange::Net(net) => net.to_string(),
+            ClientRange::Pool(pool) => format!("pool:{}", pool),
+        }
+    }
+}
+
+// Accounts for listeners started with `auth=true`. `password` is an argon2
+// or bcrypt hash in its usual string form, and `chain` replaces the
+// listener's start chain for this user.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct User {
+    pub password: String,
+    #[serde(default)]
+    pub chain: Option<String>,
+}
+
+// Idle upstream connections kept by the HTTP listeners. Unset values fall
+// back to hyper's defaults.
+#[derive(Debug, Serialize, Deserialize, Default, Clone, Copy, PartialEq)]
+pub struct HttpPool {
+    #[serde(default, with = "humantime_serde")]
+    pub idle_timeout: Option<Duration>,
+    #[serde(default)]
+    pub max_idle_per_host: Option<usize>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Timeouts {
+    #[serde(default, with = "humantime_serde")]
+    pub handshake: Option<Duration>,
+    #[serde(default, with = "humantime_serde")]
+    pub connect: Option<Duration>,
+    #[serde(default, with = "humantime_serde")]
+    pub socks: Option<Duration>,
+    #[serde(default, with = "humantime_serde")]
+    pub idle: Option<Duration>,
+}
+
+impl Timeouts {
+    pub fn or(self, other: Timeouts) -> Timeouts {
+        Timeouts {
+            handshake: self.handshake.or(other.handshake),
+            connect: self.connect.or(other.connect),
+            socks: self.socks.or(other.socks),
+            idle: self.idle.or(other.idle),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone, PartialEq)]
+pub struct GeoIp {
+    #[serde(default)]
+    pub country_database: Option<PathBuf>,
+    #[serde(default)]
+    pub asn_database: Option<PathBuf>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone, PartialEq)]
+pub struct Tls {
+    #[serde(default)]
+    pub certificates: Vec<CertificateFiles>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct CertificateFiles {
+    pub server_names: Vec<String>,
+    pub certificate: PathBuf,
+    pub key: PathBuf,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+pub struct Routing {
+    #[serde(default)]
+    pub domain_strategy: DomainStrategy,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DomainStrategy {
+    #[default]
+    AsIs,
+    IpIfNonMatch,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone, PartialEq)]
+pub struct Dns {
+    #[serde(default)]
+    pub servers: Vec<SocketAddr>,
+    #[serde(default)]
+    pub hosts: HashMap<String, Vec<IpAddr>>,
+    #[serde(default)]
+    pub cache_size: Option<usize>,
+    #[serde(default, with = "humantime_serde")]
+    pub min_ttl: Option<Duration>,
+    #[serde(default, with = "humantime_serde")]
+    pub max_ttl: Option<Duration>,
+    #[serde(default)]
+    pub family: AddressFamily,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AddressFamily {
+    PreferV4,
+    #[default]
+    PreferV6,
+    V4Only,
+    V6Only,
 }
 
 #[derive(Debug, Serialize, Deserialize, Default, Clone)]
 pub struct Stash {
     #[serde(default)]
     pub domain_pools: HashMap<String, DomainPool>,
+    #[serde(default)]
+    pub ip_pools: HashMap<String, IpPool>,
+    #[serde(default)]
+    pub player_pools: HashMap<String, PlayerPool>,
 }
 
 #[derive(Serialize, Deserialize, Default, Clone)]
@@ -38,12 +299,159 @@ impl std::fmt::Debug for DomainPool {
     }
 }
 
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+pub struct IpPool(#[serde(default)] pub BTreeSet<IpNet>);
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+pub struct PlayerPool(#[serde(default)] pub BTreeSet<String>);
+
 #[derive(Debug, Serialize, Deserialize, Default, Clone)]
 pub struct ChainRule {
     #[serde(default)]
     pub filter: ChainFilter,
-    #[serde(default)]
+    #[serde(default, deserialize_with = "deserialize_action")]
     pub action: ChainAction,
+    #[serde(default)]
+    pub timeouts: Timeouts,
+    #[serde(default)]
+    pub minecraft: Minecraft,
+    #[serde(default)]
+    pub proxy_protocol: Option<ProxyProtocol>,
+    #[serde(default)]
+    pub http: HttpForwarding,
+    // Lets clients reach loopback, link-local and private addresses as well
+    // as the proxy's own listeners through this rule.
+    #[serde(default)]
+    pub allow_internal: bool,
+    #[serde(default)]
+    pub limits: Limits,
+}
+
+// Token bucket limits. `connection_rate` is new connections per second from
+// one client address and `max_client_connections` caps the connections a
+// user, or an anonymous client address, has open at once. `upload` and
+// `download` shape every connection to that many bytes per second; Bedrock
+// sessions drop the datagrams over the rate instead. Rates must be positive, as an empty bucket is never refilled at a zero rate.
+#[derive(Debug, Serialize, Deserialize, Default, Clone, Copy, PartialEq)]
+pub struct Limits {
+    #[serde(default, deserialize_with = "deserialize_positive")]
+    pub connection_rate: Option<f64>,
+    #[serde(default)]
+    pub max_client_connections: Option<usize>,
+    #[serde(default, deserialize_with = "deserialize_positive")]
+    pub upload: Option<u64>,
+    #[serde(default, deserialize_with = "deserialize_positive")]
+    pub download: Option<u64>,
+}
+
+impl Limits {
+    pub fn or(self, other: Limits) -> Limits {
+        Limits {
+            connection_rate: self.connection_rate.or(other.connection_rate),
+            max_client_connections: self.max_client_connections.or(other.max_client_connections),
+            upload: self.upload.or(other.upload),
+            download: self.download.or(other.download),
+        }
+    }
+}
+
+fn deserialize_positive<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de> + PartialOrd + Default + std::fmt::Display,
+{
+    match Option::<T>::deserialize(deserializer)? {
+        Some(value) if value > T::default() => Ok(Some(value)),
+        Some(value) => Err(serde::de::Error::custom(format!(
+            "expected a positive number, got {}",
+            value
+        ))),
+        None => Ok(None),
+    }
+}
+
+// Forwarding headers added by the HTTP listener. `hide_client` keeps the
+// client address out of them.
+#[derive(Debug, Serialize, Deserialize, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct HttpForwarding {
+    #[serde(default)]
+    pub via: Option<bool>,
+    #[serde(default)]
+    pub forwarded: Option<bool>,
+    #[serde(default)]
+    pub x_forwarded: Option<bool>,
+    #[serde(default)]
+    pub hide_client: Option<bool>,
+}
+
+impl HttpForwarding {
+    pub fn or(self, other: HttpForwarding) -> HttpForwarding {
+        HttpForwarding {
+            via: self.via.or(other.via),
+            forwarded: self.forwarded.or(other.forwarded),
+            x_forwarded: self.x_forwarded.or(other.x_forwarded),
+            hide_client: self.hide_client.or(other.hide_client),
+        }
+    }
+}
+
+// HAProxy PROXY protocol header sent to the upstream before any payload.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ProxyProtocol {
+    V1,
+    V2,
+}
+
+// Minecraft listener options. `status` answers server list pings locally,
+// `offline` only does so when the upstream cannot be reached, and
+// `disconnect` is the reason shown to players whose login cannot be routed.
+// `handshake_host` and `handshake_port` replace the address the client sent
+// before it is passed on, keeping Forge markers. With `bungeecord` set, the
+// player's address and offline UUID are passed to the backend in the
+// handshake host field (BungeeCord IP forwarding).
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+pub struct Minecraft {
+    #[serde(default)]
+    pub status: Option<McStatus>,
+    #[serde(default)]
+    pub offline: Option<McStatus>,
+    #[serde(default)]
+    pub disconnect: Option<String>,
+    #[serde(default)]
+    pub handshake_host: Option<String>,
+    #[serde(default)]
+    pub handshake_port: Option<u16>,
+    #[serde(default)]
+    pub bungeecord: Option<bool>,
+}
+
+impl Minecraft {
+    pub fn or(self, other: Minecraft) -> Minecraft {
+        Minecraft {
+            status: self.status.or(other.status),
+            offline: self.offline.or(other.offline),
+            disconnect: self.disconnect.or(other.disconnect),
+            handshake_host: self.handshake_host.or(other.handshake_host),
+            handshake_port: self.handshake_port.or(other.handshake_port),
+            bungeecord: self.bungeecord.or(other.bungeecord),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct McStatus {
+    #[serde(default)]
+    pub motd: String,
+    #[serde(default)]
+    pub version: Option<String>,
+    #[serde(default)]
+    pub protocol: Option<u32>,
+    #[serde(default)]
+    pub online_players: u32,
+    #[serde(default)]
+    pub max_players: u32,
+    #[serde(default)]
+    pub favicon: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Default, Clone)]
@@ -56,12 +464,76 @@ pub enum ChainFilter {
     DomainWildcard {
         wildcard: String,
     },
+    IpCidr {
+        cidr: IpNet,
+    },
+    IpPool {
+        pool: String,
+    },
+    GeoIp {
+        countries: BTreeSet<String>,
+    },
+    Asn {
+        numbers: BTreeSet<u32>,
+    },
+    Alpn {
+        protocols: BTreeSet<String>,
+    },
+    TlsVersion {
+        #[serde(default)]
+        min: Option<TlsVersion>,
+        #[serde(default)]
+        max: Option<TlsVersion>,
+    },
+    Ja3 {
+        fingerprints: BTreeSet<String>,
+    },
+    EncryptedClientHello {
+        present: bool,
+    },
+    PlayerName {
+        name: String,
+    },
+    PlayerPool {
+        pool: String,
+    },
+    User {
+        name: String,
+    },
 }
 
-#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum TlsVersion {
+    #[serde(rename = "SSLv3")]
+    Ssl3,
+    #[serde(rename = "TLSv1.0")]
+    Tls10,
+    #[serde(rename = "TLSv1.1")]
+    Tls11,
+    #[serde(rename = "TLSv1.2")]
+    Tls12,
+    #[serde(rename = "TLSv1.3")]
+    Tls13,
+}
+
+impl TlsVersion {
+    pub fn wire(self) -> u16 {
+        match self {
+            TlsVersion::Ssl3 => 0x0300,
+            TlsVersion::Tls10 => 0x0301,
+            TlsVersion::Tls11 => 0x0302,
+            TlsVersion::Tls12 => 0x0303,
+            TlsVersion::Tls13 => 0x0304,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
 pub enum ChainAction {
-    #[default]
-    DirectConnect,
+    DirectConnect {
+        #[serde(default)]
+        family: Option<AddressFamily>,
+    },
     GotoChain {
         chain: String,
     },
@@ -69,20 +541,85 @@ pub enum ChainAction {
         #[serde(default)]
         credentials: Option<Credentials>,
         address: String,
+        #[serde(default)]
+        dns: DnsMode,
+        #[serde(default)]
+        tls: Option<TlsClient>,
     },
     Forward {
         address: String,
+        #[serde(default)]
+        family: Option<AddressFamily>,
+    },
+    TlsWrap {
+        #[serde(default, deserialize_with = "deserialize_boxed_action")]
+        inner: Box<ChainAction>,
+        #[serde(default)]
+        tls: TlsClient,
     },
     Drop,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+impl Default for ChainAction {
+    fn default() -> Self {
+        ChainAction::DirectConnect { family: None }
+    }
+}
+
+// Actions without required parameters may be written as a bare name,
+// e.g. "DirectConnect".
+fn deserialize_action<'de, D>(deserializer: D) -> Result<ChainAction, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let value = serde_json::Value::deserialize(deserializer)?;
+    let result = serde_json::from_value(value.clone());
+    match (result, value) {
+        (Err(_), serde_json::Value::String(name)) => {
+            serde_json::from_value(serde_json::json!({ name: {} }))
+        }
+        (result, _) => result,
+    }
+    .map_err(serde::de::Error::custom)
+}
+
+fn deserialize_boxed_action<'de, D>(deserializer: D) -> Result<Box<ChainAction>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    deserialize_action(deserializer).map(Box::new)
+}
+
+// Client side TLS settings. `sni` defaults to the upstream host name and
+// `pins` holds hex SHA-256 digests of acceptable server certificates.
+#[derive(Debug, Serialize, Deserialize, Default, Clone, PartialEq, Eq, Hash)]
+pub struct TlsClient {
+    #[serde(default)]
+    pub sni: Option<String>,
+    #[serde(default)]
+    pub ca: Option<PathBuf>,
+    #[serde(default)]
+    pub certificate: Option<PathBuf>,
+    #[serde(default)]
+    pub key: Option<PathBuf>,
+    #[serde(default)]
+    pub pins: BTreeSet<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DnsMode {
+    #[default]
+    Remote,
+    Local,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
 pub struct Credentials {
     pub username: String,
     pub password: Password,
 }
 
-#[derive(Serialize, Deserialize, Clone, Zeroize, ZeroizeOnDrop)]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Hash, Zeroize, ZeroizeOnDrop)]
 pub struct Password(pub String);
 
 impl std::fmt::Debug for Password {
@@ -99,25 +636,33 @@ pub async fn get_current_config() -> Arc<Config> {
     CONFIGURATION.read().await.clone()
 }
 
-pub async fn set_new_config(config: Config) {
+// Refuses configurations that fail validation and keeps the current one.
+pub async fn set_new_config(config: Config) -> anyhow::Result<()> {
+    config.validate(&args::Args::get().bind)?;
+    dns::install(&config.dns).await;
+    geoip::install(&config.geoip).await;
+    tls::install(&config.tls).await;
     {
         let mut configuration = CONFIGURATION.write().await;
         *configuration = Arc::new(config);
     }
+    log::info!("update configuration to {:?}", get_current_config().await);
+    save_config().await;
+    Ok(())
+}
+
+pub async fn save_config() {
     let config = get_current_config().await;
-    log::info!("update configuration to {:?}", config);
-    match serde_json::to_string(config.as_ref()) {
-        Ok(contents) => match fs::write(&args::Args::get().config, contents).await {
-            Ok(_) => {
-                log::info!("saved updated configuration to disk")
-            }
-            Err(_) => {}
-        },
-        Err(_) => {}
+    if let Ok(contents) = serde_json::to_string(config.as_ref()) {
+        if fs::write(&args::Args::get().config, contents).await.is_ok() {
+            log::info!("saved configuration to disk")
+        }
     }
 }
 
-pub async fn init_config() {
+// A configuration that fails validation is an error, as listeners may refer
+// to pools missing from it.
+pub async fn init_config() -> anyhow::Result<()> {
     match fs::read(&args::Args::get().config).await {
         Ok(content_bytes) => match String::from_utf8(content_bytes) {
             Ok(content) => {
@@ -126,7 +671,7 @@ pub async fn init_config() {
                     serde_json::from_str(&content);
                 match parse_result {
                     Ok(config) => {
-                        set_new_config(config).await;
+                        set_new_config(config).await?;
                         log::info!("configuration is installed");
                     }
                     Err(error) => {
@@ -146,6 +691,7 @@ pub async fn init_config() {
             );
         }
     }
+    get_current_config().await.validate(&args::Args::get().bind)
 }
 
 #[test]
@@ -155,3 +701,117 @@ fn default_parse_test() {
     println!("config: {:?}", config);
     assert!(config.is_ok());
 }
+
+#[test]
+fn bare_action_parse_test() {
+    let rules: Vec<ChainRule> = serde_json::from_str(
+        "[{\"action\":\"DirectConnect\"},{\"action\":\"Drop\"},{\"action\":{\"DirectConnect\":{\"family\":\"V4Only\"}}}]",
+    )
+    .unwrap();
+    assert!(matches!(
+        rules[0].action,
+        ChainAction::DirectConnect { family: None }
+    ));
+    assert!(matches!(rules[1].action, ChainAction::Drop));
+    assert!(matches!(
+        rules[2].action,
+        ChainAction::DirectConnect {
+            family: Some(AddressFamily::V4Only)
+        }
+    ));
+}
+
+#[test]
+fn tls_wrap_parse_test() {
+    let rule: ChainRule = serde_json::from_str(
+        "{\"action\":{\"TlsWrap\":{\"inner\":{\"Forward\":{\"address\":\"gateway:443\"}},\"tls\":{\"sni\":\"gateway\"}}}}",
+    )
+    .unwrap();
+    match rule.action {
+        ChainAction::TlsWrap { inner, tls } => {
+            assert!(matches!(*inner, ChainAction::Forward { .. }));
+            assert_eq!(tls.sni.as_deref(), Some("gateway"));
+        }
+        action => panic!("unexpected action {:?}", action),
+    }
+    let rule: ChainRule =
+        serde_json::from_str("{\"action\":{\"TlsWrap\":{\"inner\":\"DirectConnect\"}}}").unwrap();
+    assert!(matches!(rule.action, ChainAction::TlsWrap { .. }));
+}
+
+#[test]
+fn positive_limits_parse_test() {
+    let limits: Limits = serde_json::from_str("{\"connection_rate\":0.5,\"upload\":1024}").unwrap();
+    assert_eq!(limits.connection_rate, Some(0.5));
+    assert_eq!(limits.upload, Some(1024));
+    assert!(serde_json::from_str::<Limits>("{\"upload\":0}").is_err());
+    assert!(serde_json::from_str::<Limits>("{\"download\":0}").is_err());
+    assert!(serde_json::from_str::<Limits>("{\"connection_rate\":0}").is_err());
+    assert!(serde_json::from_str::<Limits>("{\"connection_rate\":-1.0}").is_err());
+    assert!("chain=default,upload=0".parse::<args::Listener>().is_err());
+    assert!("chain=default,download=0"
+        .parse::<args::Listener>()
+        .is_err());
+    assert!("chain=default,connection_rate=-2"
+        .parse::<args::Listener>()
+        .is_err());
+    assert!("chain=default,connection_rate=NaN"
+        .parse::<args::Listener>()
+        .is_err());
+}
+
+#[test]
+fn validate_test() {
+    let listeners: Vec<args::Listener> = vec!["chain=default,deny=pool:blocked".parse().unwrap()];
+    let mut config = Config::default();
+    assert!(config.validate(&listeners).is_err());
+    config
+        .stash
+        .ip_pools
+        .insert(String::from("blocked"), IpPool::default());
+    assert!(config.validate(&listeners).is_ok());
+    config
+        .access
+        .deny
+        .push(ClientRange::Pool(String::from("office")));
+    assert!(config.validate(&listeners).is_err());
+    config.access.deny.clear();
+
+    let rule: ChainRule = serde_json::from_str(
+        "{\"action\":{\"TlsWrap\":{\"inner\":{\"GotoChain\":{\"chain\":\"gateway\"}}}}}",
+    )
+    .unwrap();
+    config.chains.insert(String::from("default"), vec![rule]);
+    assert!(config.validate(&listeners).is_err());
+    config.chains.clear();
+
+    let listeners: Vec<args::Listener> = vec!["kind=bedrock,chain=bedrock".parse().unwrap()];
+    assert!(config.validate(&listeners).is_err());
+    let chain: Vec<ChainRule> = serde_json::from_str(
+        "[{\"filter\":{\"IpCidr\":{\"cidr\":\"192.0.2.0/24\"}},\"action\":\"Drop\"},{\"action\":{\"GotoChain\":{\"chain\":\"servers\"}}}]",
+    )
+    .unwrap();
+    config.chains.insert(String::from("bedrock"), chain);
+    let chain: Vec<ChainRule> =
+        serde_json::from_str("[{\"action\":{\"Forward\":{\"address\":\"192.0.2.7:19132\"}}}]")
+            .unwrap();
+    config.chains.insert(String::from("servers"), chain);
+    assert!(config.validate(&listeners).is_ok());
+    config.chains.get_mut("servers").unwrap()[0].filter = ChainFilter::DomainWildcard {
+        wildcard: String::from("*"),
+    };
+    assert!(config.validate(&listeners).is_err());
+    let listeners: Vec<args::Listener> =
+        vec!["kind=bedrock,chain=bedrock,fallback=192.168.1.20:19132"
+            .parse()
+            .unwrap()];
+    assert!(config.validate(&listeners).is_ok());
+}
+
+#[test]
+fn minecraft_or_test() {
+    let outer: Minecraft = serde_json::from_str("{\"bungeecord\":true}").unwrap();
+    let inner: Minecraft = serde_json::from_str("{\"bungeecord\":false}").unwrap();
+    assert_eq!(inner.or(outer.clone()).bungeecord, Some(false));
+    assert_eq!(Minecraft::default().or(outer).bungeecord, Some(true));
+}
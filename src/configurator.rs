@@ -1,10 +1,16 @@
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 
 use anyhow::Ok;
 use hyper::StatusCode;
+use serde::{de::DeserializeOwned, Serialize};
+use sha2::{Digest, Sha256};
 use warp::{Filter, Reply};
 
-use crate::config::{self, Config, DomainPool};
+use crate::{
+    args,
+    config::{self, Config, DomainPool, IpPool, PlayerPool, Stash},
+    geoip, metrics, shutdown,
+};
 
 pub async fn start() -> anyhow::Result<()> {
     let config = {
@@ -19,26 +25,9 @@ pub async fn start() -> anyhow::Result<()> {
         warp::path!("config" / "stash").and(get.or(set))
     };
 
-    let domain_pools = {
-        let get = warp::get().then(get_domain_pools);
-        let set = warp::put().and(warp::body::json()).then(set_domain_pools);
-        warp::path!("config" / "stash" / "domain_pools").and(get.or(set))
-    };
-
-    let domain_pool = {
-        let path = warp::path!("config" / "stash" / "domain_pools" / String);
-        let get = warp::get().and(path).then(get_domain_pool);
-        let set = warp::put()
-            .and(path)
-            .and(warp::body::json())
-            .then(set_domain_pool);
-        let add = warp::post()
-            .and(path)
-            .and(warp::body::json())
-            .then(add_domain_pool);
-        let del = warp::delete().and(path).then(del_domain_pool);
-        get.or(set).or(add).or(del)
-    };
+    let domain_pools = pool_routes::<DomainPool>("domain_pools");
+    let ip_pools = pool_routes::<IpPool>("ip_pools");
+    let player_pools = pool_routes::<PlayerPool>("player_pools");
 
     let chains = {
         let get = warp::get().then(get_chains);
@@ -61,26 +50,83 @@ pub async fn start() -> anyhow::Result<()> {
         get.or(set).or(add).or(del)
     };
 
+    let geoip_reload = warp::post()
+        .and(warp::path!("geoip" / "reload"))
+        .then(reload_geoip);
+
+    let metrics = warp::get().and(warp::path!("metrics")).map(metrics::render);
+
+    let ready = warp::get().and(warp::path!("ready")).map(ready);
+
+    let shutdown = warp::post()
+        .and(warp::path!("shutdown"))
+        .and(warp::header::optional::<String>("authorization"))
+        .map(request_shutdown);
+
     let routes = config
         .or(stash)
         .or(domain_pools)
-        .or(domain_pool)
+        .or(ip_pools)
+        .or(player_pools)
         .or(chains)
-        .or(chain);
+        .or(chain)
+        .or(geoip_reload)
+        .or(metrics)
+        .or(ready)
+        .or(shutdown);
 
-    let addr = crate::args::Args::get().control;
-    warp::serve(routes).run(addr).await;
+    // Keeps serving while connections drain, so `/ready` can report it.
+    let addr = args::Args::get().control;
+    let (_, server) = warp::serve(routes).try_bind_ephemeral(addr)?;
+    server.await;
     Ok(())
 }
 
+fn ready() -> StatusCode {
+    if shutdown::is_draining() {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else {
+        StatusCode::OK
+    }
+}
+
+fn request_shutdown(authorization: Option<String>) -> StatusCode {
+    match &args::Args::get().control_token {
+        Some(token) if authorized(authorization.as_deref(), token) => {
+            shutdown::trigger();
+            StatusCode::ACCEPTED
+        }
+        Some(_) => StatusCode::UNAUTHORIZED,
+        None => StatusCode::FORBIDDEN,
+    }
+}
+
+fn authorized(authorization: Option<&str>, token: &str) -> bool {
+    let presented = match authorization.and_then(|value| value.strip_prefix("Bearer ")) {
+        Some(presented) => presented,
+        None => return false,
+    };
+    // Comparing digests keeps the time taken independent of the token.
+    Sha256::digest(presented.trim()) == Sha256::digest(token)
+}
+
+// Changes failing validation are refused and leave the configuration as it
+// was.
+async fn apply(config: Config, status: StatusCode) -> StatusCode {
+    if let Err(error) = config::set_new_config(config).await {
+        log::warn!("refused configuration change: {:#}", error);
+        return StatusCode::BAD_REQUEST;
+    }
+    status
+}
+
 async fn get_config() -> warp::reply::Json {
     let config = config::get_current_config().await;
     warp::reply::json(config.as_ref())
 }
 
 async fn set_config(config: Config) -> StatusCode {
-    config::set_new_config(config).await;
-    StatusCode::ACCEPTED
+    apply(config, StatusCode::ACCEPTED).await
 }
 
 async fn get_stash() -> warp::reply::Json {
@@ -91,78 +137,161 @@ async fn get_stash() -> warp::reply::Json {
 async fn set_stash(stash: config::Stash) -> StatusCode {
     let old_config = config::get_current_config().await;
     let config = Config {
-        stash: stash,
+        stash,
         ..old_config.as_ref().clone()
     };
-    config::set_new_config(config).await;
-    StatusCode::ACCEPTED
+    apply(config, StatusCode::ACCEPTED).await
 }
 
-async fn get_domain_pools() -> warp::reply::Json {
+// Pools in the stash share their endpoints: the whole collection at
+// `config/stash/<kind>` and single pools below it, where `POST` adds one
+// entry.
+trait StashPool: Serialize + DeserializeOwned + Default + Send + Sync + 'static {
+    type Item: DeserializeOwned + Ord + Send + 'static;
+
+    fn pools(stash: &Stash) -> &HashMap<String, Self>;
+    fn pools_mut(stash: &mut Stash) -> &mut HashMap<String, Self>;
+    fn items(&mut self) -> &mut BTreeSet<Self::Item>;
+}
+
+impl StashPool for DomainPool {
+    type Item = String;
+
+    fn pools(stash: &Stash) -> &HashMap<String, Self> {
+        &stash.domain_pools
+    }
+
+    fn pools_mut(stash: &mut Stash) -> &mut HashMap<String, Self> {
+        &mut stash.domain_pools
+    }
+
+    fn items(&mut self) -> &mut BTreeSet<Self::Item> {
+        &mut self.0
+    }
+}
+
+impl StashPool for IpPool {
+    type Item = ipnet::IpNet;
+
+    fn pools(stash: &Stash) -> &HashMap<String, Self> {
+        &stash.ip_pools
+    }
+
+    fn pools_mut(stash: &mut Stash) -> &mut HashMap<String, Self> {
+        &mut stash.ip_pools
+    }
+
+    fn items(&mut self) -> &mut BTreeSet<Self::Item> {
+        &mut self.0
+    }
+}
+
+impl StashPool for PlayerPool {
+    type Item = String;
+
+    fn pools(stash: &Stash) -> &HashMap<String, Self> {
+        &stash.player_pools
+    }
+
+    fn pools_mut(stash: &mut Stash) -> &mut HashMap<String, Self> {
+        &mut stash.player_pools
+    }
+
+    fn items(&mut self) -> &mut BTreeSet<Self::Item> {
+        &mut self.0
+    }
+}
+
+fn pool_routes<P: StashPool>(
+    kind: &'static str,
+) -> impl Filter<Extract = (impl Reply,), Error = warp::Rejection> + Clone {
+    let base = warp::path("config")
+        .and(warp::path("stash"))
+        .and(warp::path(kind));
+
+    let pools = {
+        let get = warp::get().then(get_pools::<P>);
+        let set = warp::put().and(warp::body::json()).then(set_pools::<P>);
+        base.and(warp::path::end()).and(get.or(set))
+    };
+
+    let pool = {
+        let path = base
+            .and(warp::path::param::<String>())
+            .and(warp::path::end());
+        let get = warp::get().and(path).then(get_pool::<P>);
+        let set = warp::put()
+            .and(path)
+            .and(warp::body::json())
+            .then(set_pool::<P>);
+        let add = warp::post()
+            .and(path)
+            .and(warp::body::json())
+            .then(add_pool::<P>);
+        let del = warp::delete().and(path).then(del_pool::<P>);
+        get.or(set).or(add).or(del)
+    };
+
+    pools.or(pool)
+}
+
+async fn get_pools<P: StashPool>() -> warp::reply::Json {
     let config = config::get_current_config().await;
-    warp::reply::json(&config.as_ref().stash.domain_pools)
+    warp::reply::json(P::pools(&config.as_ref().stash))
 }
 
-async fn set_domain_pools(domain_pools: HashMap<String, config::DomainPool>) -> StatusCode {
+async fn set_pools<P: StashPool>(pools: HashMap<String, P>) -> StatusCode {
     let old_config = config::get_current_config().await;
     let mut config = old_config.as_ref().clone();
-    config.stash.domain_pools = domain_pools;
-    config::set_new_config(config).await;
-    StatusCode::ACCEPTED
+    *P::pools_mut(&mut config.stash) = pools;
+    apply(config, StatusCode::ACCEPTED).await
 }
 
-async fn get_domain_pool(pool_name: String) -> warp::reply::Response {
+async fn get_pool<P: StashPool>(pool_name: String) -> warp::reply::Response {
     let config = config::get_current_config().await;
-    let pool = config.as_ref().stash.domain_pools.get(&pool_name);
+    let pool = P::pools(&config.as_ref().stash).get(&pool_name);
     match pool {
         Some(value) => warp::reply::json(value).into_response(),
         None => StatusCode::NOT_FOUND.into_response(),
     }
 }
 
-async fn set_domain_pool(pool_name: String, domain_pool: config::DomainPool) -> StatusCode {
+async fn set_pool<P: StashPool>(pool_name: String, pool: P) -> StatusCode {
     let old_config = config::get_current_config().await;
     let mut config = old_config.as_ref().clone();
-    let old_domain_pool = config
-        .stash
-        .domain_pools
-        .insert(String::from(pool_name), domain_pool);
-    let status = if old_domain_pool.is_some() {
+    let old_pool = P::pools_mut(&mut config.stash).insert(pool_name, pool);
+    let status = if old_pool.is_some() {
         StatusCode::ACCEPTED
     } else {
         StatusCode::CREATED
     };
-    config::set_new_config(config).await;
-    status
+    apply(config, status).await
 }
 
-async fn add_domain_pool(pool_name: String, domain: String) -> StatusCode {
+async fn add_pool<P: StashPool>(pool_name: String, item: P::Item) -> StatusCode {
     let old_config = config::get_current_config().await;
     let mut config = old_config.as_ref().clone();
     let mut status = StatusCode::ACCEPTED;
-    let domain_pool = config
-        .stash
-        .domain_pools
-        .entry(String::from(pool_name))
+    let pool = P::pools_mut(&mut config.stash)
+        .entry(pool_name)
         .or_insert_with(|| {
             status = StatusCode::CREATED;
-            DomainPool::default()
+            P::default()
         });
-    domain_pool.0.insert(domain);
-    config::set_new_config(config).await;
-    status
+    pool.items().insert(item);
+    apply(config, status).await
 }
 
-async fn del_domain_pool(pool_name: String) -> StatusCode {
+async fn del_pool<P: StashPool>(pool_name: String) -> StatusCode {
     let old_config = config::get_current_config().await;
     let mut config = old_config.as_ref().clone();
-    let removed = config.stash.domain_pools.remove(&pool_name);
-    config::set_new_config(config).await;
-    if removed.is_some() {
+    let removed = P::pools_mut(&mut config.stash).remove(&pool_name);
+    let status = if removed.is_some() {
         StatusCode::ACCEPTED
     } else {
         StatusCode::NOT_MODIFIED
-    }
+    };
+    apply(config, status).await
 }
 
 async fn get_chains() -> warp::reply::Json {
@@ -173,11 +302,10 @@ async fn get_chains() -> warp::reply::Json {
 async fn set_chains(chains: HashMap<String, Vec<config::ChainRule>>) -> StatusCode {
     let old_config = config::get_current_config().await;
     let config = Config {
-        chains: chains,
+        chains,
         ..old_config.as_ref().clone()
     };
-    config::set_new_config(config).await;
-    StatusCode::ACCEPTED
+    apply(config, StatusCode::ACCEPTED).await
 }
 
 async fn get_chain(chain_name: String) -> warp::reply::Response {
@@ -192,40 +320,52 @@ async fn get_chain(chain_name: String) -> warp::reply::Response {
 async fn set_chain(chain_name: String, chain: Vec<config::ChainRule>) -> StatusCode {
     let old_config = config::get_current_config().await;
     let mut config = old_config.as_ref().clone();
-    let old_chain = config.chains.insert(String::from(chain_name), chain);
+    let old_chain = config.chains.insert(chain_name, chain);
     let status = if old_chain.is_some() {
         StatusCode::ACCEPTED
     } else {
         StatusCode::CREATED
     };
-    config::set_new_config(config).await;
-    status
+    apply(config, status).await
 }
 
 async fn add_chain(chain_name: String, chain_route: config::ChainRule) -> StatusCode {
     let old_config = config::get_current_config().await;
     let mut config = old_config.as_ref().clone();
     let mut status = StatusCode::ACCEPTED;
-    let chain = config
-        .chains
-        .entry(String::from(chain_name))
-        .or_insert_with(|| {
-            status = StatusCode::CREATED;
-            Vec::default()
-        });
+    let chain = config.chains.entry(chain_name).or_insert_with(|| {
+        status = StatusCode::CREATED;
+        Vec::default()
+    });
     chain.push(chain_route);
-    config::set_new_config(config).await;
-    status
+    apply(config, status).await
 }
 
 async fn del_chain(chain_name: String) -> StatusCode {
     let old_config = config::get_current_config().await;
     let mut config = old_config.as_ref().clone();
     let removed = config.chains.remove(&chain_name);
-    config::set_new_config(config).await;
-    if removed.is_some() {
+    let status = if removed.is_some() {
         StatusCode::ACCEPTED
     } else {
         StatusCode::NOT_MODIFIED
+    };
+    apply(config, status).await
+}
+
+async fn reload_geoip() -> warp::reply::Response {
+    if let Err(error) = geoip::reload().await {
+        log::error!("failed to reload GeoIP databases: {:#}", error);
+        return warp::reply::with_status(format!("{:#}", error), StatusCode::INTERNAL_SERVER_ERROR)
+            .into_response();
     }
+    StatusCode::ACCEPTED.into_response()
+}
+
+#[test]
+fn authorized_test() {
+    assert!(authorized(Some("Bearer secret"), "secret"));
+    assert!(!authorized(Some("Bearer other"), "secret"));
+    assert!(!authorized(Some("Basic secret"), "secret"));
+    assert!(!authorized(None, "secret"));
 }
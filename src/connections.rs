@@ -0,0 +1,172 @@
+use std::{
+    net::SocketAddr,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+use futures::future::BoxFuture;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{OwnedSemaphorePermit, Semaphore},
+};
+
+use crate::{access, args, metrics::METRICS, shutdown};
+
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+static GLOBAL: OnceLock<Arc<Semaphore>> = OnceLock::new();
+
+pub type Handler = fn(TcpStream, Arc<args::Listener>) -> BoxFuture<'static, anyhow::Result<()>>;
+
+pub fn set_global_limit(max: usize) {
+    let _ = GLOBAL.set(Arc::new(Semaphore::new(max)));
+}
+
+// Caps the connections a listener has open at once, on top of the global cap
+// set with `--max-connections`.
+#[derive(Clone)]
+pub struct Limit {
+    listener: Option<Arc<Semaphore>>,
+}
+
+// Holds a slot with both caps until dropped.
+pub struct Permit {
+    _listener: Option<OwnedSemaphorePermit>,
+    _global: Option<OwnedSemaphorePermit>,
+}
+
+impl Limit {
+    pub fn new(max: Option<usize>) -> Self {
+        Limit {
+            listener: max.map(|max| Arc::new(Semaphore::new(max))),
+        }
+    }
+
+    // New connections are shed rather than queued once a cap is reached.
+    pub fn try_acquire(&self, client: SocketAddr) -> Option<Permit> {
+        let listener = match try_acquire(self.listener.as_ref()) {
+            Ok(permit) => permit,
+            Err(()) => {
+                METRICS.shed_by_listener.inc();
+                log::info!("listener connection limit reached; shed {}", client);
+                return None;
+            }
+        };
+        let global = match try_acquire(GLOBAL.get()) {
+            Ok(permit) => permit,
+            Err(()) => {
+                METRICS.shed_by_global.inc();
+                log::info!("global connection limit reached; shed {}", client);
+                return None;
+            }
+        };
+        Some(Permit {
+            _listener: listener,
+            _global: global,
+        })
+    }
+}
+
+fn try_acquire(semaphore: Option<&Arc<Semaphore>>) -> Result<Option<OwnedSemaphorePermit>, ()> {
+    match semaphore {
+        Some(semaphore) => semaphore
+            .clone()
+            .try_acquire_owned()
+            .map(Some)
+            .map_err(|_| ()),
+        None => Ok(None),
+    }
+}
+
+// Errors such as running out of file descriptors are usually temporary, so
+// the listener backs off and keeps accepting instead of giving up. Returns
+// `None` once shutdown was triggered.
+pub async fn accept(tcp_listener: &TcpListener) -> Option<(TcpStream, SocketAddr)> {
+    let mut backoff = MIN_ACCEPT_BACKOFF;
+    loop {
+        let accepted = tokio::select! {
+            accepted = tcp_listener.accept() => accepted,
+            _ = shutdown::cancelled() => return None,
+        };
+        match accepted {
+            Ok(accepted) => return Some(accepted),
+            Err(error) => {
+                log::warn!(
+                    "failed to accept connection: {}; retry in {:?}",
+                    error,
+                    backoff
+                );
+                tokio::select! {
+                    _ = tokio::time::sleep(backoff) => {}
+                    _ = shutdown::cancelled() => return None,
+                }
+                backoff = (backoff * 2).min(MAX_ACCEPT_BACKOFF);
+            }
+        }
+    }
+}
+
+// Accept loop shared by the TCP listeners. Clients the access lists reject
+// are handed to `refuse`, if any, so the protocol can answer before closing,
+// and never take a connection slot. Every other connection that gets a slot
+// is handed to `proxy`. Both run in tracked tasks.
+pub async fn serve(
+    listener: args::Listener,
+    protocol: &'static str,
+    proxy: Handler,
+    refuse: Option<Handler>,
+) -> anyhow::Result<()> {
+    let tcp_listener = TcpListener::bind(&listener.addr).await?;
+    let connections = Limit::new(listener.max_connections);
+    let listener = Arc::new(listener);
+
+    loop {
+        let (stream, client_addr) = match accept(&tcp_listener).await {
+            Some(accepted) => accepted,
+            None => return Ok(()),
+        };
+        if !access::allowed(&listener, client_addr).await {
+            if let Some(refuse) = refuse {
+                let listener = listener.clone();
+                shutdown::spawn(async move {
+                    if let Err(error) = refuse(stream, listener).await {
+                        log::debug!(
+                            "an error occurred refusing {} client; error = {}",
+                            protocol,
+                            error
+                        );
+                    }
+                });
+            }
+            continue;
+        }
+        let permit = match connections.try_acquire(client_addr) {
+            Some(permit) => permit,
+            None => continue,
+        };
+        let listener = listener.clone();
+        shutdown::spawn(async move {
+            let _permit = permit;
+            log::debug!("{} connection accepted: {}", protocol, &client_addr);
+            if let Err(error) = proxy(stream, listener).await {
+                log::debug!(
+                    "an error occurred in {} connection; error = {}",
+                    protocol,
+                    error
+                );
+            };
+        });
+    }
+}
+
+#[test]
+fn limit_test() {
+    let limit = Limit::new(Some(1));
+    let client = "192.0.2.1:1000".parse().unwrap();
+    let permit = limit.try_acquire(client);
+    assert!(permit.is_some());
+    assert!(limit.try_acquire(client).is_none());
+    drop(permit);
+    assert!(limit.try_acquire(client).is_some());
+}
@@ -0,0 +1,86 @@
+use std::{
+    fmt::Display,
+    net::{IpAddr, SocketAddr, UdpSocket},
+};
+
+use crate::args;
+
+// Returned when a destination was refused by the policy below, so that
+// listeners can tell such clients apart from failed connections.
+#[derive(Debug)]
+pub struct NotPermitted(pub SocketAddr);
+
+impl Display for NotPermitted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "destination {} is not permitted", self.0)
+    }
+}
+
+impl std::error::Error for NotPermitted {}
+
+pub fn is_not_permitted(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| cause.is::<NotPermitted>())
+}
+
+// Destinations clients may not reach unless a rule sets `allow_internal`:
+// loopback, link-local and private ranges, and the proxy's own listeners.
+// This is checked on the resolved address right before dialing, so a name
+// that resolves differently on a later lookup (DNS rebinding) cannot get
+// past it.
+pub fn permitted(address: SocketAddr) -> bool {
+    let address = SocketAddr::new(address.ip().to_canonical(), address.port());
+    !internal(address.ip()) && !own_listener(address)
+}
+
+fn internal(address: IpAddr) -> bool {
+    match address {
+        IpAddr::V4(address) => {
+            address.is_unspecified()
+                || address.is_loopback()
+                || address.is_link_local()
+                || address.is_private()
+                || address.is_broadcast()
+        }
+        IpAddr::V6(address) => {
+            address.is_unspecified()
+                || address.is_loopback()
+                || address.is_unicast_link_local()
+                || address.is_unique_local()
+        }
+    }
+}
+
+fn own_listener(address: SocketAddr) -> bool {
+    let args = args::Args::get();
+    let mut own = args
+        .bind
+        .iter()
+        .map(|listener| listener.addr)
+        .chain([args.control]);
+    own.any(|own| {
+        own.port() == address.port()
+            && (own.ip() == address.ip() || (own.ip().is_unspecified() && local(address.ip())))
+    })
+}
+
+// Binding only succeeds for addresses assigned to this host.
+fn local(address: IpAddr) -> bool {
+    UdpSocket::bind((address, 0)).is_ok()
+}
+
+#[test]
+fn internal_test() {
+    let internal = |address: &str| internal(address.parse().unwrap());
+    assert!(internal("127.0.0.1"));
+    assert!(internal("0.0.0.0"));
+    assert!(internal("10.1.2.3"));
+    assert!(internal("172.16.0.1"));
+    assert!(internal("192.168.1.1"));
+    assert!(internal("169.254.169.254"));
+    assert!(internal("::1"));
+    assert!(internal("fe80::1"));
+    assert!(internal("fd00::1"));
+    assert!(!internal("93.184.216.34"));
+    assert!(!internal("172.32.0.1"));
+    assert!(!internal("2606:4700::1111"));
+}
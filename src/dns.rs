@@ -0,0 +1,254 @@
+use std::{collections::HashMap, net::IpAddr, sync::Arc};
+
+use anyhow::Result;
+use hickory_resolver::{
+    config::{LookupIpStrategy, NameServerConfig, Protocol, ResolverConfig, ResolverOpts},
+    error::ResolveErrorKind,
+    system_conf, TokioAsyncResolver,
+};
+use tokio::sync::RwLock;
+
+use crate::config::{self, AddressFamily};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Family {
+    V4,
+    V6,
+}
+
+impl Family {
+    pub fn of(address: &IpAddr) -> Self {
+        match address {
+            IpAddr::V4(_) => Family::V4,
+            IpAddr::V6(_) => Family::V6,
+        }
+    }
+}
+
+impl AddressFamily {
+    pub fn families(self) -> (Family, Option<Family>) {
+        match self {
+            AddressFamily::PreferV4 => (Family::V4, Some(Family::V6)),
+            AddressFamily::PreferV6 => (Family::V6, Some(Family::V4)),
+            AddressFamily::V4Only => (Family::V4, None),
+            AddressFamily::V6Only => (Family::V6, None),
+        }
+    }
+}
+
+pub struct Resolver {
+    settings: config::Dns,
+    hosts: HashMap<String, Vec<IpAddr>>,
+    v4: TokioAsyncResolver,
+    v6: TokioAsyncResolver,
+}
+
+impl Resolver {
+    fn new(settings: &config::Dns) -> Self {
+        let (resolver_config, mut options) = if settings.servers.is_empty() {
+            system_conf::read_system_conf().unwrap_or_else(|error| {
+                log::warn!(
+                    "failed to read system DNS configuration; use the default one: {}",
+                    error
+                );
+                (ResolverConfig::default(), ResolverOpts::default())
+            })
+        } else {
+            let mut resolver_config = ResolverConfig::new();
+            for server in &settings.servers {
+                resolver_config.add_name_server(NameServerConfig::new(*server, Protocol::Udp));
+                resolver_config.add_name_server(NameServerConfig::new(*server, Protocol::Tcp));
+            }
+            (resolver_config, ResolverOpts::default())
+        };
+        if let Some(cache_size) = settings.cache_size {
+            options.cache_size = cache_size;
+        }
+        options.positive_min_ttl = settings.min_ttl;
+        options.positive_max_ttl = settings.max_ttl;
+        let hosts = settings
+            .hosts
+            .iter()
+            .map(|(host, addresses)| (normalize(host), addresses.clone()))
+            .collect();
+        let mut v4_options = options.clone();
+        v4_options.ip_strategy = LookupIpStrategy::Ipv4Only;
+        let mut v6_options = options;
+        v6_options.ip_strategy = LookupIpStrategy::Ipv6Only;
+        Resolver {
+            settings: settings.clone(),
+            hosts,
+            v4: TokioAsyncResolver::tokio(resolver_config.clone(), v4_options),
+            v6: TokioAsyncResolver::tokio(resolver_config, v6_options),
+        }
+    }
+
+    pub fn family(&self) -> AddressFamily {
+        self.settings.family
+    }
+
+    pub async fn lookup_family(&self, host: &str, family: Family) -> Result<Vec<IpAddr>> {
+        let host = normalize(host);
+        if let Ok(address) = host.parse::<IpAddr>() {
+            return Ok(Some(address)
+                .filter(|address| Family::of(address) == family)
+                .into_iter()
+                .collect());
+        }
+        if let Some(addresses) = self.hosts.get(&host) {
+            return Ok(addresses
+                .iter()
+                .filter(|address| Family::of(address) == family)
+                .copied()
+                .collect());
+        }
+        let resolver = match family {
+            Family::V4 => &self.v4,
+            Family::V6 => &self.v6,
+        };
+        let addresses: Vec<IpAddr> = match resolver.lookup_ip(host.as_str()).await {
+            Ok(lookup) => lookup.iter().collect(),
+            Err(error) => match error.kind() {
+                ResolveErrorKind::NoRecordsFound { .. } => Vec::new(),
+                _ => return Err(error.into()),
+            },
+        };
+        log::debug!("resolved \"{}\" ({:?}) to {:?}", host, family, addresses);
+        Ok(addresses)
+    }
+
+    pub async fn lookup(&self, host: &str, preference: AddressFamily) -> Result<Vec<IpAddr>> {
+        let (primary, secondary) = preference.families();
+        let (primary, secondary) =
+            futures::future::join(self.lookup_family(host, primary), async {
+                match secondary {
+                    Some(family) => self.lookup_family(host, family).await,
+                    None => Ok(Vec::new()),
+                }
+            })
+            .await;
+        let addresses = match (primary, secondary) {
+            (Err(error), Err(_)) => return Err(error),
+            (primary, secondary) => primary
+                .unwrap_or_default()
+                .into_iter()
+                .chain(secondary.unwrap_or_default())
+                .collect::<Vec<_>>(),
+        };
+        if addresses.is_empty() {
+            Err(anyhow::anyhow!("no addresses found for \"{}\"", host))
+        } else {
+            Ok(addresses)
+        }
+    }
+}
+
+fn normalize(host: &str) -> String {
+    host.trim_start_matches('[')
+        .trim_end_matches(']')
+        .trim_end_matches('.')
+        .to_lowercase()
+}
+
+lazy_static::lazy_static! {
+    static ref RESOLVER: RwLock<Option<Arc<Resolver>>> = RwLock::new(None);
+}
+
+pub async fn get_resolver() -> Arc<Resolver> {
+    if let Some(resolver) = RESOLVER.read().await.as_ref() {
+        return resolver.clone();
+    }
+    let mut resolver = RESOLVER.write().await;
+    resolver
+        .get_or_insert_with(|| Arc::new(Resolver::new(&config::Dns::default())))
+        .clone()
+}
+
+pub async fn install(settings: &config::Dns) {
+    let mut resolver = RESOLVER.write().await;
+    match resolver.as_ref() {
+        Some(current) if &current.settings == settings => {}
+        _ => {
+            log::info!("install DNS resolver with settings {:?}", settings);
+            *resolver = Some(Arc::new(Resolver::new(settings)));
+        }
+    }
+}
+
+pub async fn lookup(host: &str) -> Result<Vec<IpAddr>> {
+    let resolver = get_resolver().await;
+    resolver.lookup(host, resolver.family()).await
+}
+
+#[test]
+fn normalize_test() {
+    assert_eq!(normalize("Example.COM."), "example.com");
+    assert_eq!(normalize("[::1]"), "::1");
+    assert_eq!(normalize("nas.lan"), "nas.lan");
+}
+
+#[tokio::test]
+async fn hosts_lookup_test() {
+    let v4: IpAddr = "192.168.1.5".parse().unwrap();
+    let v6: IpAddr = "fd00::5".parse().unwrap();
+    let settings = config::Dns {
+        // Never queried: every name below is answered from the hosts map.
+        servers: vec!["127.0.0.1:9".parse().unwrap()],
+        hosts: HashMap::from([
+            (String::from("NAS.lan."), vec![v4, v6]),
+            (String::from("printer.lan"), vec![v4]),
+        ]),
+        ..Default::default()
+    };
+    let resolver = Resolver::new(&settings);
+    assert_eq!(
+        resolver
+            .lookup("nas.LAN", AddressFamily::PreferV4)
+            .await
+            .unwrap(),
+        vec![v4, v6]
+    );
+    assert_eq!(
+        resolver
+            .lookup("nas.lan.", AddressFamily::PreferV6)
+            .await
+            .unwrap(),
+        vec![v6, v4]
+    );
+    assert_eq!(
+        resolver
+            .lookup("nas.lan", AddressFamily::V4Only)
+            .await
+            .unwrap(),
+        vec![v4]
+    );
+    assert_eq!(
+        resolver
+            .lookup("nas.lan", AddressFamily::V6Only)
+            .await
+            .unwrap(),
+        vec![v6]
+    );
+    assert_eq!(
+        resolver
+            .lookup_family("printer.lan", Family::V6)
+            .await
+            .unwrap(),
+        Vec::<IpAddr>::new()
+    );
+    assert!(resolver
+        .lookup("printer.lan", AddressFamily::V6Only)
+        .await
+        .is_err());
+    assert_eq!(
+        resolver
+            .lookup("[::1]", AddressFamily::PreferV4)
+            .await
+            .unwrap(),
+        vec!["::1".parse::<IpAddr>().unwrap()]
+    );
+    assert!(resolver
+        .lookup("[::1]", AddressFamily::V4Only)
+        .await
+        .is_err());
+}
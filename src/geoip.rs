@@ -0,0 +1,264 @@
+use std::{net::IpAddr, path::Path, sync::Arc};
+
+use anyhow::{Context as _, Result};
+use maxminddb::{geoip2, Reader};
+use tokio::{fs, sync::RwLock};
+
+use crate::config;
+
+#[derive(Default)]
+pub struct Databases {
+    settings: config::GeoIp,
+    country: Option<Reader<Vec<u8>>>,
+    asn: Option<Reader<Vec<u8>>>,
+}
+
+impl Databases {
+    async fn load(settings: &config::GeoIp) -> Result<Self> {
+        Ok(Databases {
+            settings: settings.clone(),
+            country: open(&settings.country_database).await?,
+            asn: open(&settings.asn_database).await?,
+        })
+    }
+
+    pub fn country(&self, address: IpAddr) -> Option<String> {
+        let reader = self.country.as_ref()?;
+        let country: geoip2::Country = reader.lookup(address).ok()?;
+        country
+            .country
+            .and_then(|country| country.iso_code)
+            .map(str::to_owned)
+    }
+
+    pub fn asn(&self, address: IpAddr) -> Option<u32> {
+        let reader = self.asn.as_ref()?;
+        let asn: geoip2::Asn = reader.lookup(address).ok()?;
+        asn.autonomous_system_number
+    }
+}
+
+async fn open<P: AsRef<Path>>(path: &Option<P>) -> Result<Option<Reader<Vec<u8>>>> {
+    match path {
+        Some(path) => {
+            let path = path.as_ref();
+            let contents = fs::read(path)
+                .await
+                .with_context(|| format!("failed to read \"{}\"", path.display()))?;
+            let reader = Reader::from_source(contents)
+                .with_context(|| format!("failed to open \"{}\"", path.display()))?;
+            log::info!(
+                "loaded {} database from \"{}\"",
+                reader.metadata.database_type,
+                path.display()
+            );
+            Ok(Some(reader))
+        }
+        None => Ok(None),
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref DATABASES: RwLock<Arc<Databases>> = RwLock::new(Arc::new(Databases::default()));
+}
+
+pub async fn get_databases() -> Arc<Databases> {
+    DATABASES.read().await.clone()
+}
+
+pub async fn install(settings: &config::GeoIp) {
+    if &get_databases().await.settings == settings {
+        return;
+    }
+    if let Err(error) = load(settings).await {
+        log::error!("failed to load GeoIP databases: {:#}", error);
+    }
+}
+
+pub async fn reload() -> Result<()> {
+    let config = config::get_current_config().await;
+    load(&config.geoip).await
+}
+
+async fn load(settings: &config::GeoIp) -> Result<()> {
+    let databases = Databases::load(settings).await?;
+    *DATABASES.write().await = Arc::new(databases);
+    Ok(())
+}
+
+// Builds a minimal IPv4 MaxMind database mapping each network to an encoded
+// data record.
+#[cfg(test)]
+pub fn test_database(database_type: &str, networks: &[(&str, Vec<u8>)]) -> Vec<u8> {
+    #[derive(Clone, Copy)]
+    enum Record {
+        Empty,
+        Node(usize),
+        Data(usize),
+    }
+
+    let mut nodes = vec![[Record::Empty; 2]];
+    let mut data = Vec::new();
+    for (network, record) in networks {
+        let network: ipnet::Ipv4Net = network.parse().unwrap();
+        let offset = data.len();
+        data.extend_from_slice(record);
+        let bits = u32::from(network.network());
+        let mut node = 0;
+        for depth in 0..network.prefix_len() {
+            let bit = (bits >> (31 - depth) & 1) as usize;
+            if depth + 1 == network.prefix_len() {
+                nodes[node][bit] = Record::Data(offset);
+                continue;
+            }
+            node = match nodes[node][bit] {
+                Record::Node(next) => next,
+                _ => {
+                    nodes.push([Record::Empty; 2]);
+                    nodes[node][bit] = Record::Node(nodes.len() - 1);
+                    nodes.len() - 1
+                }
+            };
+        }
+    }
+
+    let node_count = nodes.len();
+    let mut database = Vec::new();
+    for node in nodes {
+        for record in node {
+            let value = match record {
+                Record::Empty => node_count,
+                Record::Node(next) => next,
+                Record::Data(offset) => node_count + 16 + offset,
+            };
+            database.extend_from_slice(&(value as u32).to_be_bytes()[1..]);
+        }
+    }
+    database.extend_from_slice(&[0; 16]);
+    database.extend_from_slice(&data);
+    database.extend_from_slice(b"\xab\xcd\xefMaxMind.com");
+    database.extend(test_map(&[
+        ("binary_format_major_version", test_uint16(2)),
+        ("binary_format_minor_version", test_uint16(0)),
+        ("build_epoch", [&[0x08, 0x02][..], &[0; 8]].concat()),
+        ("database_type", test_string(database_type)),
+        ("description", test_map(&[])),
+        ("ip_version", test_uint16(4)),
+        ("languages", vec![0x00, 0x04]),
+        ("node_count", test_uint32(node_count as u32)),
+        ("record_size", test_uint16(24)),
+    ]));
+    database
+}
+
+#[cfg(test)]
+pub fn test_country(iso_code: &str) -> Vec<u8> {
+    test_map(&[("country", test_map(&[("iso_code", test_string(iso_code))]))])
+}
+
+#[cfg(test)]
+pub fn test_asn(number: u32) -> Vec<u8> {
+    test_map(&[("autonomous_system_number", test_uint32(number))])
+}
+
+#[cfg(test)]
+fn test_string(value: &str) -> Vec<u8> {
+    [&[0x40 | value.len() as u8][..], value.as_bytes()].concat()
+}
+
+#[cfg(test)]
+fn test_uint16(value: u16) -> Vec<u8> {
+    [&[0xa2][..], &value.to_be_bytes()].concat()
+}
+
+#[cfg(test)]
+fn test_uint32(value: u32) -> Vec<u8> {
+    [&[0xc4][..], &value.to_be_bytes()].concat()
+}
+
+#[cfg(test)]
+fn test_map(entries: &[(&str, Vec<u8>)]) -> Vec<u8> {
+    let mut map = vec![0xe0 | entries.len() as u8];
+    for (key, value) in entries {
+        map.extend(test_string(key));
+        map.extend_from_slice(value);
+    }
+    map
+}
+
+#[test]
+fn lookup_test() {
+    let databases = Databases {
+        country: Some(
+            Reader::from_source(test_database(
+                "GeoLite2-Country",
+                &[
+                    ("192.0.2.0/24", test_country("DE")),
+                    ("198.51.100.0/24", test_country("NL")),
+                ],
+            ))
+            .unwrap(),
+        ),
+        asn: Some(
+            Reader::from_source(test_database(
+                "GeoLite2-ASN",
+                &[("203.0.113.0/25", test_asn(64500))],
+            ))
+            .unwrap(),
+        ),
+        ..Default::default()
+    };
+    assert_eq!(
+        databases.country("192.0.2.7".parse().unwrap()).as_deref(),
+        Some("DE")
+    );
+    assert_eq!(
+        databases
+            .country("198.51.100.1".parse().unwrap())
+            .as_deref(),
+        Some("NL")
+    );
+    assert_eq!(databases.country("10.0.0.1".parse().unwrap()), None);
+    assert_eq!(databases.asn("203.0.113.100".parse().unwrap()), Some(64500));
+    assert_eq!(databases.asn("203.0.113.200".parse().unwrap()), None);
+    assert_eq!(
+        Databases::default().country("192.0.2.7".parse().unwrap()),
+        None
+    );
+}
+
+// Reloading reads the database files again, so replaced files take effect.
+#[tokio::test]
+async fn reload_test() {
+    let path = std::env::temp_dir().join(format!("rkp-geoip-{}.mmdb", std::process::id()));
+    let settings = config::GeoIp {
+        country_database: Some(path.clone()),
+        asn_database: None,
+    };
+    std::fs::write(
+        &path,
+        test_database("GeoLite2-Country", &[("192.0.2.0/24", test_country("DE"))]),
+    )
+    .unwrap();
+    let databases = Databases::load(&settings).await.unwrap();
+    assert_eq!(
+        databases.country("192.0.2.7".parse().unwrap()).as_deref(),
+        Some("DE")
+    );
+
+    std::fs::write(
+        &path,
+        test_database("GeoLite2-Country", &[("192.0.2.0/24", test_country("FR"))]),
+    )
+    .unwrap();
+    let databases = Databases::load(&settings).await.unwrap();
+    assert_eq!(
+        databases.country("192.0.2.7".parse().unwrap()).as_deref(),
+        Some("FR")
+    );
+
+    std::fs::write(&path, b"not a database").unwrap();
+    assert!(Databases::load(&settings).await.is_err());
+    std::fs::remove_file(&path).unwrap();
+    assert!(Databases::load(&settings).await.is_err());
+}
@@ -0,0 +1,169 @@
+use std::{
+    collections::VecDeque,
+    io,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    time::Duration,
+};
+
+use anyhow::Result;
+use futures::{future::BoxFuture, stream::FuturesUnordered, FutureExt, StreamExt};
+use tokio::{
+    net::TcpStream,
+    time::{sleep, Sleep},
+};
+
+use crate::{
+    config::AddressFamily,
+    destination,
+    dns::{self, Family},
+};
+
+// RFC 8305, section 8
+const RESOLUTION_DELAY: Duration = Duration::from_millis(50);
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+type Lookup = BoxFuture<'static, Result<Vec<IpAddr>>>;
+type Attempt = BoxFuture<'static, (SocketAddr, io::Result<TcpStream>)>;
+
+// With `guarded` set, addresses refused by the destination policy are
+// skipped as if the connection attempt had failed.
+pub async fn connect(
+    host: &str,
+    port: u16,
+    family: AddressFamily,
+    guarded: bool,
+) -> Result<TcpStream> {
+    let resolver = dns::get_resolver().await;
+    let (primary, secondary) = family.families();
+    let lookup = |family: Family| -> Lookup {
+        let resolver = resolver.clone();
+        let host = host.to_owned();
+        async move { resolver.lookup_family(&host, family).await }.boxed()
+    };
+    let mut primary_lookup = Some(lookup(primary));
+    let mut secondary_lookup = secondary.map(lookup);
+    let mut resolution_delay: Option<Pin<Box<Sleep>>> = None;
+    let mut attempt_delay: Option<Pin<Box<Sleep>>> = None;
+    let mut attempts: FuturesUnordered<Attempt> = FuturesUnordered::new();
+    let mut queue = AddressQueue::default();
+    let mut postponed = Vec::new();
+    let mut can_start = true;
+    let mut last_error: Option<anyhow::Error> = None;
+
+    loop {
+        tokio::select! {
+            result = async { primary_lookup.as_mut().unwrap().await }, if primary_lookup.is_some() => {
+                primary_lookup = None;
+                match result {
+                    Ok(addresses) => queue.primary.extend(addresses),
+                    Err(error) => last_error = Some(error),
+                }
+                if resolution_delay.take().is_some() {
+                    queue.secondary.extend(postponed.drain(..));
+                }
+            }
+            result = async { secondary_lookup.as_mut().unwrap().await }, if secondary_lookup.is_some() => {
+                secondary_lookup = None;
+                match result {
+                    Ok(addresses) if primary_lookup.is_some() => {
+                        postponed = addresses;
+                        resolution_delay = Some(Box::pin(sleep(RESOLUTION_DELAY)));
+                    }
+                    Ok(addresses) => queue.secondary.extend(addresses),
+                    Err(error) => last_error = Some(error),
+                }
+            }
+            _ = async { resolution_delay.as_mut().unwrap().await }, if resolution_delay.is_some() => {
+                resolution_delay = None;
+                queue.secondary.extend(postponed.drain(..));
+            }
+            _ = async { attempt_delay.as_mut().unwrap().await }, if attempt_delay.is_some() => {
+                attempt_delay = None;
+                can_start = true;
+            }
+            Some((address, result)) = attempts.next(), if !attempts.is_empty() => {
+                match result {
+                    Ok(stream) => {
+                        log::debug!("connected to {} ({}:{})", address, host, port);
+                        return Ok(stream);
+                    }
+                    Err(error) => {
+                        log::debug!("connection attempt to {} failed: {}", address, error);
+                        last_error = Some(error.into());
+                        attempt_delay = None;
+                        can_start = true;
+                    }
+                }
+            }
+        }
+
+        if can_start {
+            while let Some(address) = queue.next() {
+                let address = SocketAddr::new(address, port);
+                if guarded && !destination::permitted(address) {
+                    log::info!(
+                        "destination {} ({}:{}) is not permitted",
+                        address,
+                        host,
+                        port
+                    );
+                    last_error = Some(destination::NotPermitted(address).into());
+                    continue;
+                }
+                log::debug!("start connection attempt to {}", address);
+                attempts.push(async move { (address, TcpStream::connect(address).await) }.boxed());
+                attempt_delay = Some(Box::pin(sleep(CONNECTION_ATTEMPT_DELAY)));
+                can_start = false;
+                break;
+            }
+        }
+
+        let resolving =
+            primary_lookup.is_some() || secondary_lookup.is_some() || resolution_delay.is_some();
+        if !resolving && attempts.is_empty() && queue.is_empty() {
+            return Err(last_error
+                .unwrap_or_else(|| anyhow::anyhow!("no addresses found for \"{}\"", host)));
+        }
+    }
+}
+
+#[derive(Default)]
+struct AddressQueue {
+    primary: VecDeque<IpAddr>,
+    secondary: VecDeque<IpAddr>,
+    secondary_turn: bool,
+}
+
+impl AddressQueue {
+    fn next(&mut self) -> Option<IpAddr> {
+        let address = if self.secondary_turn {
+            self.secondary
+                .pop_front()
+                .or_else(|| self.primary.pop_front())
+        } else {
+            self.primary
+                .pop_front()
+                .or_else(|| self.secondary.pop_front())
+        };
+        self.secondary_turn = !self.secondary_turn;
+        address
+    }
+
+    fn is_empty(&self) -> bool {
+        self.primary.is_empty() && self.secondary.is_empty()
+    }
+}
+
+#[test]
+fn address_queue_interleave_test() {
+    let mut queue = AddressQueue::default();
+    let v6: IpAddr = "::1".parse().unwrap();
+    let v4: IpAddr = "127.0.0.1".parse().unwrap();
+    queue.primary.extend([v6, v6]);
+    queue.secondary.extend([v4]);
+    assert_eq!(queue.next(), Some(v6));
+    assert_eq!(queue.next(), Some(v4));
+    assert_eq!(queue.next(), Some(v6));
+    assert_eq!(queue.next(), None);
+}
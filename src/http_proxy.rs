@@ -1,15 +1,67 @@
 use core::{task, task::Poll};
-use std::{future::Future, net::SocketAddr, pin::Pin, str::FromStr};
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
 
-use crate::chain;
-use hyper::{body::HttpBody, http, Body, Client, Request, Response, Server, Uri};
-use tokio::net::TcpStream;
+use base64::{prelude::BASE64_STANDARD, Engine};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::{
+    access, args, auth, chain,
+    config::{self, ChainAction, HttpForwarding, ProxyProtocol, Timeouts},
+    connections, limits, relay, shutdown,
+    stream::BoxedStream,
+};
+use hyper::{
+    header::{self, HeaderName, HeaderValue},
+    http::{
+        self,
+        uri::{Authority, PathAndQuery, Scheme},
+    },
+    server::conn::Http,
+    Body, Client, HeaderMap, Method, Request, Response, Uri, Version,
+};
+
+const MAX_POOLED_CLIENTS: usize = 1024;
+const VIA_PSEUDONYM: &str = "rkp";
+const AUTHENTICATION_CHALLENGE: &str = "Basic realm=\"rkp\", charset=\"UTF-8\"";
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+const X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+const HOP_BY_HOP_HEADERS: [HeaderName; 9] = [
+    header::CONNECTION,
+    HeaderName::from_static("proxy-connection"),
+    HeaderName::from_static("keep-alive"),
+    header::PROXY_AUTHENTICATE,
+    header::PROXY_AUTHORIZATION,
+    header::TE,
+    header::TRAILER,
+    header::TRANSFER_ENCODING,
+    header::UPGRADE,
+];
 
-#[derive(Debug)]
 struct HttpProxy {
-    chain: String,
+    listener: Arc<args::Listener>,
+    client: SocketAddr,
+    local: SocketAddr,
+    allowed: bool,
+    // `None` for rejected clients and once the connection limit was reached.
+    // Shared with tunnels, which outlive the service after the upgrade.
+    connection: Option<Arc<connections::Permit>>,
+    admitted: Admitted,
 }
 
+// Requests on one connection are admitted once for every rule or listener
+// whose limits they are routed by, and the permits are held until the
+// connection and its tunnels close.
+type Admitted = Arc<Mutex<HashMap<(limits::Owners, Option<String>), Arc<limits::Permit>>>>;
+
 impl hyper::service::Service<Request<Body>> for HttpProxy {
     type Response = Response<Body>;
     type Error = anyhow::Error;
@@ -20,49 +72,97 @@ impl hyper::service::Service<Request<Body>> for HttpProxy {
     }
 
     fn call(&mut self, req: Request<Body>) -> Self::Future {
-        Box::pin(proxy(req, self.chain.to_owned()))
+        if !self.allowed {
+            return Box::pin(std::future::ready(respond_status(
+                http::StatusCode::FORBIDDEN,
+            )));
+        }
+        let connection = match &self.connection {
+            Some(connection) => connection.clone(),
+            None => return Box::pin(std::future::ready(respond_overloaded())),
+        };
+        Box::pin(proxy(
+            req,
+            self.listener.clone(),
+            self.client,
+            self.local,
+            connection,
+            self.admitted.clone(),
+        ))
     }
 }
 
-struct MakeHttpProxy {
-    chain: String,
-}
-
-impl<T> hyper::service::Service<T> for MakeHttpProxy {
-    type Response = HttpProxy;
-    type Error = std::io::Error;
-    type Future = std::future::Ready<Result<Self::Response, Self::Error>>;
-
-    fn poll_ready(&mut self, _cx: &mut task::Context<'_>) -> Poll<Result<(), Self::Error>> {
-        Ok(()).into()
-    }
-
-    fn call(&mut self, _: T) -> Self::Future {
-        std::future::ready(Ok(HttpProxy {
-            chain: self.chain.clone(),
-        }))
+pub async fn actor(listener: args::Listener) -> anyhow::Result<()> {
+    let tcp_listener = TcpListener::bind(&listener.addr).await?;
+    let connections = connections::Limit::new(listener.max_connections);
+    let listener = Arc::new(listener);
+    loop {
+        let (stream, client) = match connections::accept(&tcp_listener).await {
+            Some(accepted) => accepted,
+            None => return Ok(()),
+        };
+        // Rejected clients get a 403 for every request on the connection,
+        // without taking a connection slot.
+        let allowed = access::allowed(&listener, client).await;
+        let connection = if allowed {
+            connections.try_acquire(client)
+        } else {
+            None
+        };
+        let listener = listener.clone();
+        shutdown::spawn(async move {
+            let served = serve_connection(stream, listener, client, allowed, connection);
+            if let Err(error) = served.await {
+                log::debug!("an error occurred in HTTP connection; error = {}", error);
+            }
+        });
     }
 }
 
-pub async fn actor(address: SocketAddr, chain: String) -> anyhow::Result<()> {
-    let make_service = MakeHttpProxy {
-        chain: chain.to_owned(),
+// The configuration is read for every connection, so a new handshake timeout
+// applies as soon as it is set. On shutdown the connection finishes the
+// request in flight and closes instead of waiting for the next one.
+async fn serve_connection(
+    stream: TcpStream,
+    listener: Arc<args::Listener>,
+    client: SocketAddr,
+    allowed: bool,
+    connection: Option<connections::Permit>,
+) -> anyhow::Result<()> {
+    let local = stream.local_addr()?;
+    let config = config::get_current_config().await;
+    let mut http = Http::new();
+    http.http1_preserve_header_case(true)
+        .http1_title_case_headers(true);
+    if let Some(handshake_timeout) = listener.timeouts.or(config.timeouts).handshake {
+        http.http1_header_read_timeout(handshake_timeout);
+    }
+    let service = HttpProxy {
+        listener,
+        client,
+        local,
+        allowed,
+        connection: connection.map(Arc::new),
+        admitted: Admitted::default(),
     };
-    let server = Server::try_bind(&address)?
-        .http1_preserve_header_case(true)
-        .http1_title_case_headers(true)
-        .serve(make_service);
-    Ok(server.await?)
+    let connection = http.serve_connection(stream, service).with_upgrades();
+    tokio::pin!(connection);
+    tokio::select! {
+        result = &mut connection => return Ok(result?),
+        _ = shutdown::cancelled() => {}
+    }
+    connection.as_mut().graceful_shutdown();
+    Ok(connection.await?)
 }
 
 #[derive(Clone)]
 struct ChainConnector {
-    context: chain::Context,
-    start: String,
+    route: Arc<chain::Route>,
+    context: Arc<chain::Context>,
 }
 
 impl hyper::service::Service<Uri> for ChainConnector {
-    type Response = TcpStream;
+    type Response = BoxedStream;
     type Error = anyhow::Error;
     type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
 
@@ -71,70 +171,668 @@ impl hyper::service::Service<Uri> for ChainConnector {
     }
 
     fn call(&mut self, _: Uri) -> Self::Future {
-        Box::pin(chain::connect(
-            self.context.to_owned(),
-            self.start.to_owned(),
-        ))
+        let route = self.route.clone();
+        let context = self.context.clone();
+        Box::pin(async move { route.connect(&context).await })
     }
 }
 
-async fn proxy(req: Request<Body>, chain: String) -> Result<Response<Body>, anyhow::Error> {
-    let address = match req.headers().get(hyper::header::HOST) {
-        Some(address) => match address.to_str() {
-            Ok(value) => value,
-            Err(error) => {
-                log::debug!("bad Host header; drop: {}", error);
-                return respond_status(http::StatusCode::BAD_REQUEST);
-            }
-        },
-        None => {
-            log::debug!("no Host header in http request; drop");
-            return respond_status(http::StatusCode::BAD_REQUEST);
+// Upstream connections are shared between requests that resolve to the same
+// route and target. The client address only takes part when it is sent
+// upstream in a PROXY protocol header.
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct PoolKey {
+    action: ChainAction,
+    timeouts: Timeouts,
+    proxy_protocol: Option<(ProxyProtocol, Option<SocketAddr>)>,
+    allow_internal: bool,
+    host: String,
+    port: u16,
+}
+
+struct PooledClient {
+    client: Client<ChainConnector>,
+    last_used: Instant,
+}
+
+#[derive(Default)]
+struct Pool {
+    settings: config::HttpPool,
+    clients: HashMap<PoolKey, PooledClient>,
+}
+
+lazy_static::lazy_static! {
+    static ref POOL: Mutex<Pool> = Mutex::new(Pool::default());
+}
+
+fn pooled_client(
+    route: chain::Route,
+    context: chain::Context,
+    settings: config::HttpPool,
+) -> Client<ChainConnector> {
+    let key = PoolKey {
+        action: route.action.clone(),
+        timeouts: route.timeouts,
+        proxy_protocol: route
+            .proxy_protocol
+            .map(|version| (version, context.client)),
+        allow_internal: route.allow_internal,
+        host: context.host.clone(),
+        port: context.port,
+    };
+    let mut pool = POOL.lock().unwrap();
+    if pool.settings != settings {
+        pool.settings = settings;
+        pool.clients.clear();
+    }
+    // Once full, the least recently used client makes room, so the busy
+    // targets keep their idle connections.
+    if pool.clients.len() >= MAX_POOLED_CLIENTS && !pool.clients.contains_key(&key) {
+        let oldest = pool
+            .clients
+            .iter()
+            .min_by_key(|(_, pooled)| pooled.last_used)
+            .map(|(key, _)| key.clone());
+        if let Some(oldest) = oldest {
+            pool.clients.remove(&oldest);
+        }
+    }
+    let pooled = pool.clients.entry(key).or_insert_with(|| {
+        let mut builder = Client::builder();
+        builder
+            .http1_title_case_headers(true)
+            .http1_preserve_header_case(true)
+            .set_host(false);
+        if let Some(idle_timeout) = settings.idle_timeout {
+            builder.pool_idle_timeout(idle_timeout);
+        }
+        if let Some(max_idle_per_host) = settings.max_idle_per_host {
+            builder.pool_max_idle_per_host(max_idle_per_host);
+        }
+        PooledClient {
+            client: builder.build(ChainConnector {
+                route: Arc::new(route),
+                context: Arc::new(context),
+            }),
+            last_used: Instant::now(),
         }
+    });
+    pooled.last_used = Instant::now();
+    pooled.client.clone()
+}
+
+impl hyper::client::connect::Connection for BoxedStream {
+    fn connected(&self) -> hyper::client::connect::Connected {
+        hyper::client::connect::Connected::new()
+    }
+}
+
+async fn proxy(
+    req: Request<Body>,
+    listener: Arc<args::Listener>,
+    client: SocketAddr,
+    local: SocketAddr,
+    connection: Arc<connections::Permit>,
+    admitted: Admitted,
+) -> Result<Response<Body>, anyhow::Error> {
+    let user = if listener.auth {
+        match proxy_user(req.headers()).await {
+            Some(user) => Some(user),
+            None => return respond_authentication_required(),
+        }
+    } else {
+        None
     };
-    let uri = Uri::from_str(&format!("proto://{}", address))?;
-    let host = uri.host().unwrap();
-    let port = match uri.port() {
-        Some(port) => port.as_u16(),
-        None => 80,
+    let (user, start) = match user {
+        Some((name, user)) => (Some(name), user.chain.unwrap_or(listener.chain.clone())),
+        None => (None, listener.chain.clone()),
+    };
+    let target = match request_target(&req) {
+        Ok(target) => target,
+        Err(reason) => {
+            log::debug!("bad request target \"{}\": {}; drop", req.uri(), reason);
+            return respond_status(http::StatusCode::BAD_REQUEST);
+        }
     };
-    let address = format!("{}:{}", host, port);
     let context = chain::Context {
-        host: host.to_owned(),
-        port: port,
-        address: address.to_owned(),
-    };
-    let connector = ChainConnector {
-        context: context,
-        start: chain.to_owned(),
-    };
-    let client = Client::builder()
-        .http1_title_case_headers(true)
-        .http1_preserve_header_case(true)
-        .set_host(false)
-        .build(connector);
-    let old_uri = req.uri();
-    let new_uri = Uri::builder().scheme("http").authority(address);
-    let new_uri = if let Some(value) = old_uri.path_and_query() {
-        new_uri.path_and_query(value.to_owned())
-    } else {
-        new_uri
+        host: target.host().to_owned(),
+        port: target.port(),
+        user,
+        client: Some(client),
+        local: Some(local),
+        ..Default::default()
+    };
+    let route = chain::route(&context, &start, &listener).await;
+    let permit = match admit(&admitted, &route, &context) {
+        Ok(permit) => permit,
+        Err(error) => {
+            log::info!("{}", error);
+            return respond_status(http::StatusCode::TOO_MANY_REQUESTS);
+        }
+    };
+    let path = match target.path.clone() {
+        Some(path) => path,
+        None => return tunnel(req, route, context, (connection, permit)).await,
     };
-    let new_uri = new_uri
-        .path_and_query(old_uri.path_and_query().unwrap().to_owned())
-        .build()?;
-    let builder = Request::builder()
+    let forwarding = route.http.or(listener.http);
+    let limits = route.limits;
+    let config = config::get_current_config().await;
+    let upstream = pooled_client(route, context, config.http_pool);
+    let new_uri = upstream_uri(&target, path)?;
+    let mut headers = req.headers().clone();
+    strip_hop_by_hop(&mut headers);
+    // An absolute-form target overrides whatever the Host header said.
+    headers.insert(
+        header::HOST,
+        HeaderValue::from_str(target.authority.as_str())?,
+    );
+    add_forwarding_headers(
+        &mut headers,
+        &forwarding,
+        req.version(),
+        target.authority.as_str(),
+        client,
+        local,
+    );
+    let mut new_req = Request::builder()
         .method(req.method())
         .uri(new_uri)
-        .version(req.version());
-    let builder = req
+        .version(req.version())
+        .body(limits::throttle_body(req.into_body(), limits.upload))?;
+    *new_req.headers_mut() = headers;
+    let mut response = match upstream.request(new_req).await {
+        Ok(response) => response,
+        Err(error) => {
+            log::info!(
+                "upstream request to \"{}\" failed: {:#}",
+                target.authority,
+                error
+            );
+            return respond_status(http::StatusCode::BAD_GATEWAY);
+        }
+    };
+    strip_hop_by_hop(response.headers_mut());
+    if forwarding.via == Some(true) {
+        let via = via(response.version());
+        append_header(response.headers_mut(), header::VIA, &via);
+    }
+    Ok(response.map(|body| limits::throttle_body(body, limits.download)))
+}
+
+fn admit(
+    admitted: &Admitted,
+    route: &chain::Route,
+    context: &chain::Context,
+) -> anyhow::Result<Arc<limits::Permit>> {
+    let key = (route.limit_owners.clone(), context.user.clone());
+    let mut admitted = admitted.lock().unwrap();
+    if let Some(permit) = admitted.get(&key) {
+        return Ok(permit.clone());
+    }
+    let permit = Arc::new(limits::admit(route, context)?);
+    admitted.insert(key, permit.clone());
+    Ok(permit)
+}
+
+async fn proxy_user(headers: &HeaderMap) -> Option<(String, config::User)> {
+    let (username, password) = basic_credentials(headers.get(header::PROXY_AUTHORIZATION)?)?;
+    let user = auth::authenticate(&username, &password).await?;
+    Some((username, user))
+}
+
+fn basic_credentials(value: &HeaderValue) -> Option<(String, String)> {
+    let (scheme, credentials) = value.to_str().ok()?.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let credentials = BASE64_STANDARD.decode(credentials.trim()).ok()?;
+    let (username, password) = String::from_utf8(credentials)
+        .ok()?
+        .split_once(':')
+        .map(|(username, password)| (username.to_owned(), password.to_owned()))?;
+    Some((username, password))
+}
+
+fn respond_authentication_required() -> Result<Response<Body>, anyhow::Error> {
+    Ok(Response::builder()
+        .status(http::StatusCode::PROXY_AUTHENTICATION_REQUIRED)
+        .header(header::PROXY_AUTHENTICATE, AUTHENTICATION_CHALLENGE)
+        .body(Body::empty())?)
+}
+
+// Where a request goes, taken from its request target (RFC 9112, section
+// 3.2). A missing path means a CONNECT tunnel.
+#[derive(Debug, PartialEq)]
+struct Target {
+    authority: Authority,
+    path: Option<PathAndQuery>,
+}
+
+impl Target {
+    fn host(&self) -> &str {
+        let host = self.authority.host();
+        host.strip_prefix('[')
+            .and_then(|host| host.strip_suffix(']'))
+            .unwrap_or(host)
+    }
+
+    fn port(&self) -> u16 {
+        self.authority.port_u16().unwrap_or(80)
+    }
+}
+
+fn request_target<B>(req: &Request<B>) -> Result<Target, &'static str> {
+    let uri = req.uri();
+    if req.method() == Method::CONNECT {
+        // authority-form
+        let authority = match (uri.scheme(), uri.authority()) {
+            (None, Some(authority)) if authority.port().is_some() => without_userinfo(authority)?,
+            _ => return Err("CONNECT target must be host:port"),
+        };
+        return Ok(Target {
+            authority,
+            path: None,
+        });
+    }
+    if let Some(scheme) = uri.scheme() {
+        // absolute-form
+        if scheme != &Scheme::HTTP {
+            return Err("unsupported scheme");
+        }
+        let authority = without_userinfo(uri.authority().ok_or("missing authority")?)?;
+        let path = uri
+            .path_and_query()
+            .filter(|path| !path.as_str().is_empty())
+            .cloned()
+            .unwrap_or_else(|| PathAndQuery::from_static("/"));
+        return Ok(Target {
+            authority,
+            path: Some(path),
+        });
+    }
+    // origin-form and asterisk-form
+    let path = match uri.path_and_query() {
+        Some(path) if path.as_str().starts_with('/') => path.clone(),
+        Some(path) if path.as_str() == "*" && req.method() == Method::OPTIONS => path.clone(),
+        _ => return Err("unexpected request target form"),
+    };
+    let host = req
         .headers()
-        .into_iter()
-        .fold(builder, |builder, (key, value)| builder.header(key, value));
-    let new_req = builder.body(req.boxed())?;
-    Ok(client.request(new_req).await?)
+        .get(header::HOST)
+        .ok_or("missing Host header")?;
+    let authority = host
+        .to_str()
+        .ok()
+        .and_then(|host| Authority::from_str(host).ok())
+        .ok_or("bad Host header")?;
+    let authority = without_userinfo(&authority)?;
+    Ok(Target {
+        authority,
+        path: Some(path),
+    })
+}
+
+// Userinfo is deprecated in http URIs (RFC 9110, section 4.2.4) and would be
+// passed on in the Host and forwarding headers, so such targets are refused.
+fn without_userinfo(authority: &Authority) -> Result<Authority, &'static str> {
+    if authority.as_str().contains('@') {
+        return Err("userinfo in request target");
+    }
+    Ok(authority.clone())
+}
+
+// The client needs an absolute URI to pick a pooled connection and writes
+// only its path and query on the request line. Joining the parts as text
+// would glue an asterisk-form target onto the authority, so they are set
+// one by one and `OPTIONS *` reaches the origin unchanged.
+fn upstream_uri(target: &Target, path: PathAndQuery) -> Result<Uri, http::Error> {
+    let authority = Authority::from_str(&format!("{}:{}", target.authority.host(), target.port()))?;
+    let mut parts = http::uri::Parts::default();
+    parts.scheme = Some(Scheme::HTTP);
+    parts.authority = Some(authority);
+    parts.path_and_query = Some(path);
+    Ok(Uri::from_parts(parts)?)
+}
+
+// CONNECT: reply once the upstream is connected, then relay whatever the
+// client sends over the upgraded connection.
+async fn tunnel(
+    req: Request<Body>,
+    route: chain::Route,
+    context: chain::Context,
+    permits: (Arc<connections::Permit>, Arc<limits::Permit>),
+) -> Result<Response<Body>, anyhow::Error> {
+    let mut upstream = match route.connect(&context).await {
+        Ok(upstream) => upstream,
+        Err(error) => {
+            log::info!(
+                "failed to connect to \"{}:{}\": {:#}",
+                context.host,
+                context.port,
+                error
+            );
+            return respond_status(http::StatusCode::BAD_GATEWAY);
+        }
+    };
+    shutdown::spawn(async move {
+        let _permits = permits;
+        let mut stream = match hyper::upgrade::on(req).await {
+            Ok(stream) => stream,
+            Err(error) => {
+                log::debug!("CONNECT upgrade failed: {}", error);
+                return;
+            }
+        };
+        if let Err(error) = relay::copy_bidirectional(
+            &mut stream,
+            &mut upstream,
+            route.timeouts.idle,
+            &route.limits,
+        )
+        .await
+        {
+            log::debug!("an error occurred in CONNECT tunnel; error = {}", error);
+        }
+    });
+    respond_status(http::StatusCode::OK)
+}
+
+// RFC 9110, section 7.6.1: Connection and the headers it lists only apply to
+// a single hop, as do the legacy and proxy specific ones below.
+fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    let listed: Vec<HeaderName> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::from_str(name.trim()).ok())
+        .collect();
+    for name in listed {
+        headers.remove(name);
+    }
+    for name in HOP_BY_HOP_HEADERS {
+        headers.remove(name);
+    }
+}
+
+fn add_forwarding_headers(
+    headers: &mut HeaderMap,
+    forwarding: &HttpForwarding,
+    version: Version,
+    host: &str,
+    client: SocketAddr,
+    local: SocketAddr,
+) {
+    let hide_client = forwarding.hide_client == Some(true);
+    if forwarding.via == Some(true) {
+        append_header(headers, header::VIA, &via(version));
+    }
+    if forwarding.forwarded == Some(true) {
+        let client = if hide_client {
+            String::from("unknown")
+        } else {
+            forwarded_node(client.ip())
+        };
+        let value = format!(
+            "for={};by={};host=\"{}\";proto=http",
+            client,
+            forwarded_node(local.ip()),
+            host
+        );
+        append_header(headers, header::FORWARDED, &value);
+    }
+    if forwarding.x_forwarded == Some(true) {
+        if !hide_client {
+            append_header(headers, X_FORWARDED_FOR, &client.ip().to_string());
+        }
+        if let Ok(host) = HeaderValue::from_str(host) {
+            headers.insert(X_FORWARDED_HOST, host);
+        }
+        headers.insert(X_FORWARDED_PROTO, HeaderValue::from_static("http"));
+    }
+}
+
+fn via(version: Version) -> String {
+    let version = match version {
+        Version::HTTP_09 => "0.9",
+        Version::HTTP_10 => "1.0",
+        Version::HTTP_2 => "2",
+        Version::HTTP_3 => "3",
+        _ => "1.1",
+    };
+    format!("{} {}", version, VIA_PSEUDONYM)
+}
+
+// RFC 7239, section 6: IPv6 nodes are bracketed and quoted.
+fn forwarded_node(address: IpAddr) -> String {
+    match address {
+        IpAddr::V4(address) => address.to_string(),
+        IpAddr::V6(address) => format!("\"[{}]\"", address),
+    }
+}
+
+fn append_header(headers: &mut HeaderMap, name: HeaderName, value: &str) {
+    let value = match headers.get(&name).and_then(|it| it.to_str().ok()) {
+        Some(existing) => format!("{}, {}", existing, value),
+        None => value.to_owned(),
+    };
+    if let Ok(value) = HeaderValue::from_str(&value) {
+        headers.insert(name, value);
+    }
+}
+
+fn respond_overloaded() -> Result<Response<Body>, anyhow::Error> {
+    Ok(Response::builder()
+        .status(http::StatusCode::SERVICE_UNAVAILABLE)
+        .header(header::CONNECTION, "close")
+        .body(Body::empty())?)
 }
 
 fn respond_status(status: http::StatusCode) -> Result<Response<Body>, anyhow::Error> {
     Ok(Response::builder().status(status).body(Body::empty())?)
 }
+
+#[test]
+fn strip_hop_by_hop_test() {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::CONNECTION,
+        HeaderValue::from_static("close, x-trace"),
+    );
+    headers.insert("x-trace", HeaderValue::from_static("1"));
+    headers.insert("keep-alive", HeaderValue::from_static("timeout=5"));
+    headers.insert("proxy-connection", HeaderValue::from_static("keep-alive"));
+    headers.insert(
+        header::PROXY_AUTHORIZATION,
+        HeaderValue::from_static("Basic eA=="),
+    );
+    headers.insert(header::TE, HeaderValue::from_static("trailers"));
+    headers.insert(header::ACCEPT, HeaderValue::from_static("*/*"));
+    strip_hop_by_hop(&mut headers);
+    assert_eq!(headers.len(), 1);
+    assert!(headers.contains_key(header::ACCEPT));
+}
+
+#[test]
+fn forwarding_headers_test() {
+    let client = "192.0.2.1:50000".parse().unwrap();
+    let local = "[2001:db8::1]:8080".parse().unwrap();
+    let forwarding = HttpForwarding {
+        via: Some(true),
+        forwarded: Some(true),
+        x_forwarded: Some(true),
+        hide_client: None,
+    };
+    let mut headers = HeaderMap::new();
+    headers.insert(X_FORWARDED_FOR, HeaderValue::from_static("198.51.100.7"));
+    add_forwarding_headers(
+        &mut headers,
+        &forwarding,
+        Version::HTTP_11,
+        "example.com",
+        client,
+        local,
+    );
+    assert_eq!(headers[header::VIA], "1.1 rkp");
+    assert_eq!(
+        headers[header::FORWARDED],
+        "for=192.0.2.1;by=\"[2001:db8::1]\";host=\"example.com\";proto=http"
+    );
+    assert_eq!(headers[X_FORWARDED_FOR], "198.51.100.7, 192.0.2.1");
+    assert_eq!(headers[X_FORWARDED_HOST], "example.com");
+    assert_eq!(headers[X_FORWARDED_PROTO], "http");
+
+    let forwarding = HttpForwarding {
+        hide_client: Some(true),
+        ..forwarding
+    };
+    let mut headers = HeaderMap::new();
+    add_forwarding_headers(
+        &mut headers,
+        &forwarding,
+        Version::HTTP_11,
+        "example.com",
+        client,
+        local,
+    );
+    assert!(headers[header::FORWARDED]
+        .to_str()
+        .unwrap()
+        .starts_with("for=unknown;"));
+    assert!(!headers.contains_key(X_FORWARDED_FOR));
+}
+
+#[test]
+fn request_target_test() {
+    let target = |method: Method, uri: &str, host: Option<&str>| {
+        let mut req = Request::builder().method(method).uri(uri);
+        if let Some(host) = host {
+            req = req.header(header::HOST, host);
+        }
+        request_target(&req.body(()).unwrap())
+    };
+    // origin-form
+    let origin = target(Method::GET, "/a?b", Some("example.com:8080")).unwrap();
+    assert_eq!(origin.authority, "example.com:8080");
+    assert_eq!(origin.port(), 8080);
+    assert_eq!(origin.path.unwrap(), "/a?b");
+    assert!(target(Method::GET, "/", None).is_err());
+    // absolute-form ignores the Host header
+    let absolute = target(Method::GET, "http://[::1]/", Some("other")).unwrap();
+    assert_eq!(absolute.host(), "::1");
+    assert_eq!(absolute.port(), 80);
+    assert_eq!(absolute.path.unwrap(), "/");
+    assert!(target(Method::GET, "https://example.com/", None).is_err());
+    assert!(target(Method::GET, "http://user:pw@example.com/", None).is_err());
+    assert!(target(Method::GET, "/", Some("user@example.com")).is_err());
+    // authority-form
+    let connect = target(Method::CONNECT, "example.com:443", None).unwrap();
+    assert_eq!(connect.port(), 443);
+    assert_eq!(connect.path, None);
+    assert!(target(Method::CONNECT, "example.com", None).is_err());
+    assert!(target(Method::CONNECT, "user:pw@example.com:443", None).is_err());
+    assert!(target(Method::GET, "example.com:443", None).is_err());
+    // asterisk-form
+    let asterisk = target(Method::OPTIONS, "*", Some("example.com")).unwrap();
+    assert_eq!(asterisk.path.unwrap(), "*");
+    assert!(target(Method::GET, "*", Some("example.com")).is_err());
+}
+
+#[tokio::test]
+async fn upstream_request_line_test() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let origin = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let route = chain::Route {
+        action: ChainAction::Forward {
+            address: origin.local_addr().unwrap().to_string(),
+            family: None,
+        },
+        ..Default::default()
+    };
+    let context = chain::Context {
+        host: String::from("example.com"),
+        port: 80,
+        ..Default::default()
+    };
+    let upstream = pooled_client(route, context, config::HttpPool::default());
+    let req = Request::builder()
+        .method(Method::OPTIONS)
+        .uri("*")
+        .header(header::HOST, "example.com")
+        .body(())
+        .unwrap();
+    let target = request_target(&req).unwrap();
+    let path = target.path.clone().unwrap();
+    let new_req = Request::builder()
+        .method(Method::OPTIONS)
+        .uri(upstream_uri(&target, path).unwrap())
+        .header(header::HOST, "example.com")
+        .body(Body::empty())
+        .unwrap();
+    let request = tokio::spawn(upstream.request(new_req));
+    let (mut stream, _) = origin.accept().await.unwrap();
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        let mut byte = [0u8];
+        stream.read_exact(&mut byte).await.unwrap();
+        head.push(byte[0]);
+    }
+    assert!(head.starts_with(b"OPTIONS * HTTP/1.1\r\n"));
+    stream
+        .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+        .await
+        .unwrap();
+    let response = request.await.unwrap().unwrap();
+    assert_eq!(response.status(), http::StatusCode::NO_CONTENT);
+
+    let origin_form = Target {
+        authority: Authority::from_static("example.com"),
+        path: None,
+    };
+    let uri = upstream_uri(&origin_form, PathAndQuery::from_static("/a?b")).unwrap();
+    assert_eq!(uri, "http://example.com:80/a?b");
+}
+
+#[test]
+fn basic_credentials_test() {
+    let value = HeaderValue::from_static("Basic dXNlcjpwYTpzcw==");
+    assert_eq!(
+        basic_credentials(&value),
+        Some(("user".to_owned(), "pa:ss".to_owned()))
+    );
+    assert_eq!(
+        basic_credentials(&HeaderValue::from_static("Bearer dXNlcjpwYXNz")),
+        None
+    );
+    assert_eq!(
+        basic_credentials(&HeaderValue::from_static("Basic !!")),
+        None
+    );
+}
+
+// Keep-alive requests reuse the permit their connection was admitted with.
+#[test]
+fn admit_test() {
+    let limits = config::Limits {
+        connection_rate: Some(1.0),
+        max_client_connections: Some(1),
+        ..Default::default()
+    };
+    let route = chain::Route {
+        limits,
+        limit_owners: limits::Owners::new(&limits, "listener 192.0.2.100:8080"),
+        ..Default::default()
+    };
+    let context = chain::Context {
+        client: Some("192.0.2.3:1000".parse().unwrap()),
+        ..Default::default()
+    };
+    let admitted = Admitted::default();
+    let permit = admit(&admitted, &route, &context).unwrap();
+    assert!(Arc::ptr_eq(
+        &permit,
+        &admit(&admitted, &route, &context).unwrap()
+    ));
+    assert!(admit(&Admitted::default(), &route, &context).is_err());
+}
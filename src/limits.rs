@@ -0,0 +1,377 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    net::IpAddr,
+    pin::Pin,
+    sync::Mutex,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use anyhow::Result;
+use hyper::{body::HttpBody, Body};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{Instant, Sleep},
+};
+
+use crate::{chain, config::Limits, metrics::METRICS};
+
+const MAX_TRACKED_CLIENTS: usize = 65536;
+const STALE_BUCKET: Duration = Duration::from_secs(60);
+
+// Holds one second's worth of tokens at most.
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn new(rate: f64) -> Self {
+        Bucket {
+            tokens: rate.max(1.0),
+            last: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, rate: f64) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(rate.max(1.0));
+        self.last = now;
+    }
+}
+
+// Names the rule or listener each stateful limit was taken from. Buckets and
+// counters are kept per owner and client, so rules and listeners with
+// different settings never share them.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Owners {
+    connection_rate: Option<String>,
+    max_client_connections: Option<String>,
+}
+
+impl Owners {
+    pub fn new(limits: &Limits, owner: &str) -> Self {
+        Owners {
+            connection_rate: limits.connection_rate.map(|_| owner.to_owned()),
+            max_client_connections: limits.max_client_connections.map(|_| owner.to_owned()),
+        }
+    }
+
+    pub fn or(self, other: Owners) -> Owners {
+        Owners {
+            connection_rate: self.connection_rate.or(other.connection_rate),
+            max_client_connections: self.max_client_connections.or(other.max_client_connections),
+        }
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref CONNECTION_BUCKETS: Mutex<HashMap<(String, IpAddr), Bucket>> =
+        Mutex::new(HashMap::new());
+    static ref ACTIVE_CONNECTIONS: Mutex<HashMap<(String, String), usize>> =
+        Mutex::new(HashMap::new());
+}
+
+// Counts towards `max_client_connections` until dropped.
+pub struct Permit {
+    key: Option<(String, String)>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Some(key) = &self.key {
+            let mut active = ACTIVE_CONNECTIONS.lock().unwrap();
+            if let Some(count) = active.get_mut(key) {
+                *count -= 1;
+                if *count == 0 {
+                    active.remove(key);
+                }
+            }
+        }
+    }
+}
+
+// The connection rate is counted per client address. Concurrent connections
+// are counted per user when the client authenticated, per address otherwise.
+pub fn admit(route: &chain::Route, context: &chain::Context) -> Result<Permit> {
+    let limits = &route.limits;
+    let owners = &route.limit_owners;
+    let address = context.client.map(|client| client.ip().to_canonical());
+    if let (Some(rate), Some(address)) = (limits.connection_rate, address) {
+        let mut buckets = CONNECTION_BUCKETS.lock().unwrap();
+        if buckets.len() >= MAX_TRACKED_CLIENTS {
+            buckets.retain(|_, bucket| bucket.last.elapsed() < STALE_BUCKET);
+        }
+        let owner = owners.connection_rate.clone().unwrap_or_default();
+        let bucket = buckets
+            .entry((owner, address))
+            .or_insert_with(|| Bucket::new(rate));
+        bucket.refill(rate);
+        if bucket.tokens < 1.0 {
+            METRICS.rate_limited.inc();
+            return Err(anyhow::anyhow!(
+                "connection rate limit reached for {}",
+                address
+            ));
+        }
+        bucket.tokens -= 1.0;
+    }
+    let client = match &context.user {
+        Some(user) => Some(format!("user {}", user)),
+        None => address.map(|address| address.to_string()),
+    };
+    let key = match (limits.max_client_connections, client) {
+        (Some(max), Some(client)) => {
+            let key = (
+                owners.max_client_connections.clone().unwrap_or_default(),
+                client,
+            );
+            let mut active = ACTIVE_CONNECTIONS.lock().unwrap();
+            let count = active.get(&key).copied().unwrap_or(0);
+            if count >= max {
+                METRICS.concurrency_limited.inc();
+                return Err(anyhow::anyhow!("connection limit reached for {}", key.1));
+            }
+            active.insert(key.clone(), count + 1);
+            Some(key)
+        }
+        _ => None,
+    };
+    Ok(Permit { key })
+}
+
+// Shapes reads to `rate` bytes per second. A read may overdraw the bucket,
+// in which case the next one waits until it is paid back.
+pub struct Throttled<S> {
+    inner: S,
+    rate: Option<f64>,
+    bucket: Bucket,
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> Throttled<S> {
+    pub fn new(inner: S, rate: Option<u64>) -> Self {
+        let rate = rate.map(|rate| rate as f64);
+        Throttled {
+            inner,
+            rate,
+            bucket: Bucket::new(rate.unwrap_or_default()),
+            delay: None,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Throttled<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let rate = match this.rate {
+            Some(rate) => rate,
+            None => return Pin::new(&mut this.inner).poll_read(cx, buf),
+        };
+        loop {
+            if let Some(delay) = &mut this.delay {
+                ready!(delay.as_mut().poll(cx));
+                this.delay = None;
+            }
+            this.bucket.refill(rate);
+            if this.bucket.tokens >= 1.0 {
+                break;
+            }
+            let wait = Duration::from_secs_f64((1.0 - this.bucket.tokens) / rate);
+            this.delay = Some(Box::pin(tokio::time::sleep(wait)));
+        }
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        this.bucket.tokens -= (buf.filled().len() - filled) as f64;
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Throttled<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+// Shapes a message body to `rate` bytes per second the same way. Bodies known
+// to be empty are passed on untouched, so they keep being sent without one.
+pub fn throttle_body(body: Body, rate: Option<u64>) -> Body {
+    let rate = match rate {
+        Some(rate) if !body.is_end_stream() => rate as f64,
+        _ => return body,
+    };
+    let chunks = futures::stream::unfold(
+        (body, Bucket::new(rate)),
+        move |(mut body, mut bucket)| async move {
+            bucket.refill(rate);
+            while bucket.tokens < 1.0 {
+                let wait = Duration::from_secs_f64((1.0 - bucket.tokens) / rate);
+                tokio::time::sleep(wait).await;
+                bucket.refill(rate);
+            }
+            let chunk = body.data().await?;
+            if let Ok(chunk) = &chunk {
+                bucket.tokens -= chunk.len() as f64;
+            }
+            Some((chunk, (body, bucket)))
+        },
+    );
+    Body::wrap_stream(chunks)
+}
+
+// Polices datagrams to `rate` bytes per second. UDP gives no backpressure to
+// slow a sender down with, so datagrams over the rate are dropped instead.
+pub struct Policer {
+    rate: Option<f64>,
+    bucket: Mutex<Bucket>,
+}
+
+impl Policer {
+    pub fn new(rate: Option<u64>) -> Self {
+        let rate = rate.map(|rate| rate as f64);
+        Policer {
+            rate,
+            bucket: Mutex::new(Bucket::new(rate.unwrap_or_default())),
+        }
+    }
+
+    pub fn allow(&self, length: usize) -> bool {
+        let rate = match self.rate {
+            Some(rate) => rate,
+            None => return true,
+        };
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.refill(rate);
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= length as f64;
+        true
+    }
+}
+
+#[cfg(test)]
+fn test_route(limits: Limits, owner: &str) -> chain::Route {
+    chain::Route {
+        limits,
+        limit_owners: Owners::new(&limits, owner),
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn admit_test() {
+    let route = test_route(
+        Limits {
+            connection_rate: Some(2.0),
+            max_client_connections: Some(1),
+            ..Default::default()
+        },
+        "listener 192.0.2.100:1080",
+    );
+    let context = chain::Context {
+        client: Some("192.0.2.1:1000".parse().unwrap()),
+        ..Default::default()
+    };
+    let permit = admit(&route, &context).unwrap();
+    assert!(admit(&route, &context).is_err());
+    drop(permit);
+    // The second attempt above used up the remaining token.
+    assert!(admit(&route, &context).is_err());
+}
+
+// Each rule keeps its own bucket and counter for a client, sized by its own
+// settings.
+#[tokio::test]
+async fn admit_owners_test() {
+    let strict = test_route(
+        Limits {
+            connection_rate: Some(1.0),
+            max_client_connections: Some(1),
+            ..Default::default()
+        },
+        "chain \"default\" rule 0",
+    );
+    let generous = test_route(
+        Limits {
+            connection_rate: Some(100.0),
+            max_client_connections: Some(10),
+            ..Default::default()
+        },
+        "chain \"default\" rule 1",
+    );
+    let context = chain::Context {
+        client: Some("192.0.2.2:1000".parse().unwrap()),
+        ..Default::default()
+    };
+    let permit = admit(&strict, &context).unwrap();
+    assert!(admit(&strict, &context).is_err());
+    let permits: Vec<_> = (0..10)
+        .map(|_| admit(&generous, &context).unwrap())
+        .collect();
+    assert!(admit(&generous, &context).is_err());
+    drop(permits);
+    drop(permit);
+    assert!(admit(&strict, &context).is_err());
+    assert!(admit(&generous, &context).is_ok());
+}
+
+#[tokio::test]
+async fn throttled_test() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let (mut writer, reader) = tokio::io::duplex(65536);
+    writer.write_all(&[0; 25000]).await.unwrap();
+    drop(writer);
+    let mut reader = Throttled::new(reader, Some(20000));
+    let start = Instant::now();
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data).await.unwrap();
+    assert_eq!(data.len(), 25000);
+    assert!(start.elapsed() >= Duration::from_millis(200));
+}
+
+#[tokio::test]
+async fn throttle_body_test() {
+    assert!(throttle_body(Body::empty(), Some(20000)).is_end_stream());
+
+    let chunks: Vec<Result<Vec<u8>, std::convert::Infallible>> =
+        vec![Ok(vec![0; 12500]), Ok(vec![0; 12500])];
+    let body = throttle_body(
+        Body::wrap_stream(futures::stream::iter(chunks)),
+        Some(20000),
+    );
+    let start = Instant::now();
+    let data = hyper::body::to_bytes(body).await.unwrap();
+    assert_eq!(data.len(), 25000);
+    assert!(start.elapsed() >= Duration::from_millis(200));
+}
+
+#[tokio::test]
+async fn policer_test() {
+    let policer = Policer::new(Some(10000));
+    assert!(policer.allow(15000));
+    assert!(!policer.allow(1));
+    tokio::time::sleep(Duration::from_millis(600)).await;
+    assert!(policer.allow(1));
+    assert!(Policer::new(None).allow(usize::MAX));
+}
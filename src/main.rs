@@ -1,19 +1,62 @@
+mod access;
 mod args;
+mod auth;
+mod bedrock_proxy;
 mod chain;
+mod client_hello;
 mod config;
 mod configurator;
+mod connections;
+mod destination;
+mod dns;
+mod geoip;
+mod happy_eyeballs;
 mod http_proxy;
+mod limits;
 mod logging;
 mod mc_proxy;
+mod metrics;
+mod proxy_protocol;
+mod relay;
 mod server;
+mod shutdown;
+mod socks_proxy;
+mod stream;
+mod timeout;
+mod tls;
 mod tls_proxy;
+mod tls_terminate;
 
 #[tokio::main]
 async fn main() {
     logging::init_logging();
-    config::init_config().await;
+    if let Err(error) = config::init_config().await {
+        log::error!("invalid configuration: {:#}", error);
+        log::logger().flush();
+        std::process::exit(1);
+    }
 
-    futures::future::try_join(configurator::start(), server::start())
-        .await
-        .unwrap();
+    tokio::spawn(shutdown::watch_signals());
+
+    // Listeners return once shutdown is triggered; the control server keeps
+    // running until connections have drained, then the configuration is
+    // persisted.
+    let listeners = async {
+        let result = server::start().await;
+        shutdown::trigger();
+        shutdown::drain(args::Args::get().drain_timeout).await;
+        config::save_config().await;
+        result
+    };
+    let result = tokio::select! {
+        result = configurator::start() => result,
+        result = listeners => result,
+    };
+    if let Err(error) = &result {
+        log::error!("{}", error);
+    }
+    log::logger().flush();
+    if result.is_err() {
+        std::process::exit(1);
+    }
 }
@@ -1,30 +1,50 @@
-use std::{fmt::Display, net::SocketAddr};
+use std::{fmt::Display, net::IpAddr, time::Duration};
 
+use md5::{Digest, Md5};
+
+use futures::FutureExt;
 use tokio::{
     io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
-    net::{TcpListener, TcpStream},
+    net::TcpStream,
 };
 
-use crate::chain::{self, Context};
-
-pub async fn actor(address: SocketAddr, chain: String) -> anyhow::Result<()> {
-    let listener = TcpListener::bind(&address).await?;
+use crate::{
+    args,
+    chain::{self, Context},
+    config::{self, McStatus},
+    connections, limits, relay,
+    timeout::{self, Phase},
+};
 
-    loop {
-        let chain = chain.clone();
-        let (stream, client_addr) = listener.accept().await?;
-        tokio::spawn(async move {
-            log::debug!("Minecraft connection accepted: {}", &client_addr);
-            if let Err(error) = proxy(stream, chain).await {
-                log::debug!(
-                    "an error occurred in Minecraft connection; error = {}",
-                    error
-                );
-            };
-        });
-    }
+pub async fn actor(listener: args::Listener) -> anyhow::Result<()> {
+    connections::serve(
+        listener,
+        "Minecraft",
+        |stream, listener| async move { proxy(stream, &listener).await }.boxed(),
+        None,
+    )
+    .await
 }
 
+const HANDSHAKE: u32 = 0x00;
+const STATUS: u32 = 1;
+const LOGIN: u32 = 2;
+const LOGIN_START: u32 = 0x00;
+const LOGIN_DISCONNECT: u32 = 0x00;
+const MAX_PLAYER_NAME_LENGTH: usize = 16;
+const STATUS_REQUEST: u32 = 0x00;
+const STATUS_RESPONSE: u32 = 0x00;
+const PING: u32 = 0x01;
+const PONG: u32 = 0x01;
+const MAX_PACKET_LENGTH: usize = 2 * 1024 * 1024;
+
+const LEGACY_PING: u8 = 0xFE;
+const LEGACY_KICK: u8 = 0xFF;
+const LEGACY_PING_CHANNEL: &str = "MC|PingHost";
+const LEGACY_PROTOCOL: u32 = 127;
+const LEGACY_GRACE: Duration = Duration::from_millis(200);
+const MAX_LEGACY_DATA_LENGTH: usize = 1024;
+
 const SEGMENT_BITS: u32 = 0x7F;
 const CONTINUE_BIT: u32 = 0x80;
 
@@ -38,7 +58,7 @@ trait McAsyncReadExt: AsyncRead {
         let mut position: usize = 0;
         loop {
             let byte: u32 = self.read_u8().await?.into();
-            value = value | ((byte & SEGMENT_BITS) << position);
+            value |= (byte & SEGMENT_BITS) << position;
             if (byte & CONTINUE_BIT) == 0 {
                 break Ok(value);
             };
@@ -87,7 +107,7 @@ trait McAsyncWriteExt: AsyncWrite {
                 break;
             }
             write_byte((value & SEGMENT_BITS) | CONTINUE_BIT);
-            value = value >> 7;
+            value >>= 7;
         }
         self.write_all(&buf[..position]).await
     }
@@ -102,43 +122,451 @@ trait McAsyncWriteExt: AsyncWrite {
     }
 }
 
-impl McAsyncReadExt for TcpStream {}
-impl McAsyncWriteExt for TcpStream {}
+impl<T: AsyncRead + ?Sized> McAsyncReadExt for T {}
+impl<T: AsyncWrite + ?Sized> McAsyncWriteExt for T {}
+
+struct Handshake {
+    version: u32,
+    original_host: String,
+    port: u16,
+    next_state: u32,
+}
 
-async fn proxy(mut stream: TcpStream, chain: String) -> anyhow::Result<()> {
-    let length = stream.read_varint().await?;
+async fn read_handshake(stream: &mut TcpStream) -> anyhow::Result<Handshake> {
+    let _length = stream.read_varint().await?;
     let packet_id = stream.read_varint().await?;
-    validate(0, packet_id, "packet ID")?;
+    validate(HANDSHAKE, packet_id, "packet ID")?;
     let version = stream.read_varint().await?;
     let original_host = stream.read_varstring().await?;
-    let host = un_fml_address(&original_host);
     let port = stream.read_u16().await?;
-    let address = format!("{}:{}", host, port);
     let next_state = stream.read_varint().await?;
+    Ok(Handshake {
+        version,
+        original_host,
+        port,
+        next_state,
+    })
+}
+
+async fn write_handshake<W>(stream: &mut W, handshake: &Handshake) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin + Send,
+{
+    let mut body = Vec::new();
+    body.write_varint(handshake.version).await?;
+    body.write_varstring(&handshake.original_host).await?;
+    body.write_u16(handshake.port).await?;
+    body.write_varint(handshake.next_state).await?;
+    write_packet(stream, HANDSHAKE, &body).await
+}
+
+// BungeeCord IP forwarding: "host\0client ip\0uuid" with the offline mode UUID
+// written without dashes, as the backend expects in that mode. Forge markers
+// cannot be kept since the backend reads the fourth field as properties.
+fn bungeecord_host(host: &str, client: IpAddr, player: &str) -> String {
+    format!("{}\0{}\0{}", host, client, offline_uuid(player))
+}
+
+fn offline_uuid(player: &str) -> String {
+    let mut uuid = Md5::digest(format!("OfflinePlayer:{}", player).as_bytes());
+    uuid[6] = (uuid[6] & 0x0f) | 0x30;
+    uuid[8] = (uuid[8] & 0x3f) | 0x80;
+    uuid.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+async fn proxy(mut stream: TcpStream, listener: &args::Listener) -> anyhow::Result<()> {
+    let config = config::get_current_config().await;
+    let handshake_timeout = listener.timeouts.or(config.timeouts).handshake;
+    let mut first = [0u8; 1];
+    timeout::run(Phase::Handshake, handshake_timeout, async {
+        Ok(stream.peek(&mut first).await?)
+    })
+    .await?;
+    if first[0] == LEGACY_PING {
+        return legacy_proxy(stream, listener, handshake_timeout).await;
+    }
+    let mut handshake = timeout::run(
+        Phase::Handshake,
+        handshake_timeout,
+        read_handshake(&mut stream),
+    )
+    .await?;
+    let mut login_start = match handshake.next_state {
+        LOGIN if listener.sniff_login => Some(
+            timeout::run(
+                Phase::Handshake,
+                handshake_timeout,
+                read_login_start(&mut stream),
+            )
+            .await?,
+        ),
+        _ => None,
+    };
+    let context = Context {
+        host: un_fml_address(&handshake.original_host),
+        port: handshake.port,
+        player: login_start.as_ref().map(|login| login.player.clone()),
+        client: stream.peer_addr().ok(),
+        local: stream.local_addr().ok(),
+        ..Default::default()
+    };
+    let route = chain::route(&context, &listener.chain, listener).await;
+    if handshake.next_state == STATUS {
+        if let Some(status) = &route.minecraft.status {
+            return respond_status(&mut stream, status, &handshake, handshake_timeout).await;
+        }
+    }
+    if let Some(host) = &route.minecraft.handshake_host {
+        handshake.original_host = rewrite_address(&handshake.original_host, host);
+    }
+    if let Some(port) = route.minecraft.handshake_port {
+        handshake.port = port;
+    }
+    if handshake.next_state == LOGIN && route.minecraft.bungeecord == Some(true) {
+        let login = match login_start {
+            Some(login) => login,
+            None => {
+                timeout::run(
+                    Phase::Handshake,
+                    handshake_timeout,
+                    read_login_start(&mut stream),
+                )
+                .await?
+            }
+        };
+        let client = stream.peer_addr()?;
+        let host = route
+            .minecraft
+            .handshake_host
+            .as_deref()
+            .unwrap_or(&context.host);
+        handshake.original_host = bungeecord_host(host, client.ip(), &login.player);
+        login_start = Some(login);
+    }
+    let _permit = limits::admit(&route, &context)?;
+    let mut proxy = match route.connect(&context).await {
+        Ok(proxy) => proxy,
+        Err(error) => match (&route.minecraft.offline, handshake.next_state) {
+            (Some(status), STATUS) => {
+                log::debug!(
+                    "upstream is unavailable, reply with offline status: {}",
+                    error
+                );
+                return respond_status(&mut stream, status, &handshake, handshake_timeout).await;
+            }
+            (_, LOGIN) => {
+                let reason = route
+                    .minecraft
+                    .disconnect
+                    .as_deref()
+                    .unwrap_or("Unable to connect to the server");
+                disconnect(&mut stream, reason).await?;
+                return Err(error);
+            }
+            _ => return Err(error),
+        },
+    };
+    write_handshake(&mut proxy, &handshake).await?;
+    if let Some(login_start) = login_start {
+        write_packet(&mut proxy, LOGIN_START, &login_start.body).await?;
+    }
+    relay::copy_bidirectional(&mut stream, &mut proxy, route.timeouts.idle, &route.limits).await?;
+    Ok(())
+}
+
+// Pre-1.7 server list ping: 0xFE, then 0x01 since 1.4, then an MC|PingHost
+// plugin message carrying the protocol version, host and port since 1.6.
+struct LegacyPing {
+    raw: Vec<u8>,
+    extended: bool,
+    protocol: Option<u8>,
+    host: Option<String>,
+    port: Option<u16>,
+}
+
+async fn read_legacy_ping<R>(stream: &mut R) -> anyhow::Result<LegacyPing>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    let mut ping = LegacyPing {
+        raw: vec![stream.read_u8().await?],
+        extended: false,
+        protocol: None,
+        host: None,
+        port: None,
+    };
+    validate(LEGACY_PING, ping.raw[0], "legacy ping")?;
+    // Older clients send nothing more, so the optional bytes are only
+    // waited for briefly.
+    for expected in [0x01, 0xFA] {
+        match tokio::time::timeout(LEGACY_GRACE, stream.read_u8()).await {
+            Ok(Ok(byte)) => {
+                validate(expected, byte, "legacy ping")?;
+                ping.raw.push(byte);
+                ping.extended = true;
+            }
+            _ => return Ok(ping),
+        }
+    }
+    let channel_length = stream.read_u16().await? as usize;
+    let mut channel = vec![0u8; channel_length * 2];
+    stream.read_exact(&mut channel).await?;
+    validate(
+        LEGACY_PING_CHANNEL,
+        decode_utf16(&channel)?.as_str(),
+        "plugin channel",
+    )?;
+    let data_length = stream.read_u16().await? as usize;
+    if data_length > MAX_LEGACY_DATA_LENGTH {
+        return Err(anyhow::anyhow!("legacy ping data is too big"));
+    }
+    let mut data = vec![0u8; data_length];
+    stream.read_exact(&mut data).await?;
+    ping.raw
+        .extend_from_slice(&(channel_length as u16).to_be_bytes());
+    ping.raw.extend_from_slice(&channel);
+    ping.raw
+        .extend_from_slice(&(data_length as u16).to_be_bytes());
+    ping.raw.extend_from_slice(&data);
+
+    let mut data = &data[..];
+    ping.protocol = Some(data.read_u8().await?);
+    let host_length = data.read_u16().await? as usize;
+    let mut host = vec![0u8; host_length * 2];
+    data.read_exact(&mut host).await?;
+    ping.host = Some(decode_utf16(&host)?);
+    ping.port = Some(u16::try_from(data.read_i32().await?)?);
+    Ok(ping)
+}
+
+fn decode_utf16(bytes: &[u8]) -> anyhow::Result<String> {
+    let units: Vec<u16> = bytes
+        .chunks_exact(2)
+        .map(|unit| u16::from_be_bytes([unit[0], unit[1]]))
+        .collect();
+    Ok(String::from_utf16(&units)?)
+}
+
+async fn legacy_proxy(
+    mut stream: TcpStream,
+    listener: &args::Listener,
+    handshake_timeout: Option<Duration>,
+) -> anyhow::Result<()> {
+    let ping = timeout::run(
+        Phase::Handshake,
+        handshake_timeout,
+        read_legacy_ping(&mut stream),
+    )
+    .await?;
+    let (host, port, trusted) = match (&ping.host, &listener.fallback) {
+        (Some(host), _) => (
+            host.clone(),
+            ping.port.unwrap_or(listener.addr.port()),
+            false,
+        ),
+        (None, Some(fallback)) => {
+            let (host, port) = chain::split_address(fallback)?;
+            (host.to_owned(), port, true)
+        }
+        (None, None) => (String::new(), listener.addr.port(), false),
+    };
     let context = Context {
-        host: host.clone(),
-        port: port,
-        address: address,
+        host: un_fml_address(&host),
+        port,
+        trusted,
+        client: stream.peer_addr().ok(),
+        local: stream.local_addr().ok(),
+        ..Default::default()
+    };
+    log::debug!(
+        "legacy Minecraft ping for \"{}:{}\"",
+        context.host,
+        context.port
+    );
+    let route = chain::route(&context, &listener.chain, listener).await;
+    if let Some(status) = &route.minecraft.status {
+        return respond_legacy_status(&mut stream, status, &ping).await;
+    }
+    let _permit = limits::admit(&route, &context)?;
+    let mut proxy = match route.connect(&context).await {
+        Ok(proxy) => proxy,
+        Err(error) => match &route.minecraft.offline {
+            Some(status) => {
+                log::debug!(
+                    "upstream is unavailable, reply with offline status: {}",
+                    error
+                );
+                return respond_legacy_status(&mut stream, status, &ping).await;
+            }
+            None => return Err(error),
+        },
+    };
+    proxy.write_all(&ping.raw).await?;
+    relay::copy_bidirectional(&mut stream, &mut proxy, route.timeouts.idle, &route.limits).await?;
+    Ok(())
+}
+
+async fn respond_legacy_status<W>(
+    stream: &mut W,
+    status: &McStatus,
+    ping: &LegacyPing,
+) -> anyhow::Result<()>
+where
+    W: AsyncWrite + Unpin + Send,
+{
+    let text = if ping.extended {
+        let protocol = status
+            .protocol
+            .or(ping.protocol.map(u32::from))
+            .unwrap_or(LEGACY_PROTOCOL);
+        format!(
+            "\u{a7}1\0{}\0{}\0{}\0{}\0{}",
+            protocol,
+            status.version.as_deref().unwrap_or("rkp"),
+            status.motd,
+            status.online_players,
+            status.max_players
+        )
+    } else {
+        format!(
+            "{}\u{a7}{}\u{a7}{}",
+            status.motd, status.online_players, status.max_players
+        )
     };
-    let mut proxy = chain::connect(context, chain).await?;
-    proxy.write_varint(length).await?;
-    proxy.write_varint(packet_id).await?;
-    proxy.write_varint(version).await?;
-    proxy.write_varstring(&original_host).await?;
-    proxy.write_u16(port).await?;
-    proxy.write_varint(next_state).await?;
-    tokio::io::copy_bidirectional(&mut stream, &mut proxy).await?;
+    let units: Vec<u16> = text.encode_utf16().collect();
+    let mut packet = vec![LEGACY_KICK];
+    packet.extend_from_slice(&(units.len() as u16).to_be_bytes());
+    for unit in units {
+        packet.extend_from_slice(&unit.to_be_bytes());
+    }
+    stream.write_all(&packet).await?;
+    stream.flush().await?;
     Ok(())
 }
 
+struct LoginStart {
+    player: String,
+    body: Vec<u8>,
+}
+
+// Only the leading username is parsed; the rest of the packet differs between
+// protocol versions and is forwarded untouched.
+async fn read_login_start<R>(stream: &mut R) -> anyhow::Result<LoginStart>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    let (packet_id, body) = read_packet(stream).await?;
+    validate(LOGIN_START, packet_id, "packet ID")?;
+    let player = (&body[..]).read_varstring().await?;
+    if player.is_empty() || player.len() > MAX_PLAYER_NAME_LENGTH {
+        return Err(anyhow::anyhow!("bad player name \"{}\"", player));
+    }
+    Ok(LoginStart { player, body })
+}
+
+async fn disconnect<W>(stream: &mut W, reason: &str) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin + Send,
+{
+    let reason = serde_json::json!({ "text": reason }).to_string();
+    let mut body = Vec::new();
+    body.write_varstring(&reason).await?;
+    write_packet(stream, LOGIN_DISCONNECT, &body).await
+}
+
+async fn respond_status<S>(
+    stream: &mut S,
+    status: &McStatus,
+    handshake: &Handshake,
+    handshake_timeout: Option<Duration>,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let mut response = serde_json::json!({
+        "version": {
+            "name": status.version.as_deref().unwrap_or("rkp"),
+            "protocol": status.protocol.unwrap_or(handshake.version),
+        },
+        "players": {
+            "max": status.max_players,
+            "online": status.online_players,
+            "sample": [],
+        },
+        "description": { "text": status.motd },
+    });
+    if let Some(favicon) = &status.favicon {
+        response["favicon"] = favicon.as_str().into();
+    }
+    timeout::run(Phase::Handshake, handshake_timeout, async {
+        let (packet_id, _) = read_packet(stream).await?;
+        validate(STATUS_REQUEST, packet_id, "packet ID")?;
+        let mut body = Vec::new();
+        body.write_varstring(&response.to_string()).await?;
+        write_packet(stream, STATUS_RESPONSE, &body).await?;
+        // The client may close the connection instead of pinging.
+        let (packet_id, payload) = match read_packet(stream).await {
+            Ok(packet) => packet,
+            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(error) => return Err(error.into()),
+        };
+        validate(PING, packet_id, "packet ID")?;
+        write_packet(stream, PONG, &payload).await?;
+        Ok(())
+    })
+    .await
+}
+
+async fn read_packet<R>(stream: &mut R) -> io::Result<(u32, Vec<u8>)>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    let length = stream.read_varint().await? as usize;
+    if length > MAX_PACKET_LENGTH {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "packet is too big",
+        ));
+    }
+    let mut packet = vec![0u8; length];
+    stream.read_exact(&mut packet).await?;
+    let mut body = &packet[..];
+    let packet_id = body.read_varint().await?;
+    Ok((packet_id, body.to_vec()))
+}
+
+async fn write_packet<W>(stream: &mut W, packet_id: u32, body: &[u8]) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin + Send,
+{
+    let mut packet = Vec::new();
+    packet.write_varint(packet_id).await?;
+    packet.extend_from_slice(body);
+    let mut framed = Vec::new();
+    framed.write_varint(packet.len() as u32).await?;
+    framed.extend_from_slice(&packet);
+    stream.write_all(&framed).await?;
+    stream.flush().await
+}
+
 fn un_fml_address(address: &str) -> String {
-    let null = address.find('\0');
-    match null {
-        Some(index) => address[..index].to_owned(),
-        None => address.to_owned(),
+    split_fml_address(address).0.to_owned()
+}
+
+// Forge clients append a marker such as "\0FML\0", "\0FML2\0" or "\0FML3\0"
+// to the server address; everything from the first NUL is kept as is.
+fn split_fml_address(address: &str) -> (&str, &str) {
+    match address.find('\0') {
+        Some(index) => address.split_at(index),
+        None => (address, ""),
     }
 }
 
+fn rewrite_address(address: &str, host: &str) -> String {
+    let (_, marker) = split_fml_address(address);
+    format!("{}{}", host, marker)
+}
+
 fn validate<T>(expect: T, actual: T, field_name: &str) -> anyhow::Result<()>
 where
     T: Eq + Display,
@@ -154,3 +582,127 @@ where
         Err(anyhow::Error::msg(message))
     }
 }
+
+#[tokio::test]
+async fn status_response_test() {
+    let (mut client, mut server) = tokio::io::duplex(1024);
+    let handshake = Handshake {
+        version: 763,
+        original_host: String::from("mc.example.com"),
+        port: 25565,
+        next_state: STATUS,
+    };
+    let status = McStatus {
+        motd: String::from("offline"),
+        version: None,
+        protocol: None,
+        online_players: 0,
+        max_players: 20,
+        favicon: None,
+    };
+    let server =
+        tokio::spawn(async move { respond_status(&mut server, &status, &handshake, None).await });
+    write_packet(&mut client, STATUS_REQUEST, &[])
+        .await
+        .unwrap();
+    let (packet_id, body) = read_packet(&mut client).await.unwrap();
+    assert_eq!(packet_id, STATUS_RESPONSE);
+    let json = (&body[..]).read_varstring().await.unwrap();
+    let json: serde_json::Value = serde_json::from_str(&json).unwrap();
+    assert_eq!(json["version"]["protocol"], 763);
+    assert_eq!(json["description"]["text"], "offline");
+    write_packet(&mut client, PING, &42u64.to_be_bytes())
+        .await
+        .unwrap();
+    let (packet_id, body) = read_packet(&mut client).await.unwrap();
+    assert_eq!(packet_id, PONG);
+    assert_eq!(body, 42u64.to_be_bytes());
+    server.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn login_start_test() {
+    let mut body = Vec::new();
+    body.write_varstring("Notch").await.unwrap();
+    body.extend_from_slice(&[0x01; 16]);
+    let mut packet = Vec::new();
+    write_packet(&mut packet, LOGIN_START, &body).await.unwrap();
+    let login_start = read_login_start(&mut &packet[..]).await.unwrap();
+    assert_eq!(login_start.player, "Notch");
+    assert_eq!(login_start.body, body);
+}
+
+#[tokio::test]
+async fn legacy_ping_test() {
+    let utf16 = |text: &str| -> Vec<u8> {
+        let units: Vec<u16> = text.encode_utf16().collect();
+        let mut bytes = (units.len() as u16).to_be_bytes().to_vec();
+        units
+            .iter()
+            .for_each(|unit| bytes.extend_from_slice(&unit.to_be_bytes()));
+        bytes
+    };
+    let mut data = vec![74];
+    data.extend(utf16("mc.example.com"));
+    data.extend_from_slice(&25565i32.to_be_bytes());
+    let mut packet = vec![0xFE, 0x01, 0xFA];
+    packet.extend(utf16("MC|PingHost"));
+    packet.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    packet.extend(data);
+
+    let ping = read_legacy_ping(&mut &packet[..]).await.unwrap();
+    assert!(ping.extended);
+    assert_eq!(ping.protocol, Some(74));
+    assert_eq!(ping.host.as_deref(), Some("mc.example.com"));
+    assert_eq!(ping.port, Some(25565));
+    assert_eq!(ping.raw, packet);
+
+    let ping = read_legacy_ping(&mut &[0xFEu8][..]).await.unwrap();
+    assert!(!ping.extended);
+    assert!(ping.host.is_none());
+}
+
+#[test]
+fn bungeecord_host_test() {
+    assert_eq!(
+        bungeecord_host("mc.example.com", "192.0.2.1".parse().unwrap(), "Notch"),
+        "mc.example.com\x00192.0.2.1\x00b50ad385829d3141a2167e7d7539ba7f"
+    );
+}
+
+#[test]
+fn rewrite_address_test() {
+    let cases = [
+        ("mc.example.com", "backend.internal"),
+        ("mc.example.com\0FML\0", "backend.internal\0FML\0"),
+        ("mc.example.com\0FML2\0", "backend.internal\0FML2\0"),
+        ("mc.example.com\0FML3\0", "backend.internal\0FML3\0"),
+    ];
+    for (address, expected) in cases {
+        assert_eq!(rewrite_address(address, "backend.internal"), expected);
+        assert_eq!(un_fml_address(address), "mc.example.com");
+    }
+}
+
+#[tokio::test]
+async fn write_handshake_test() {
+    let handshake = Handshake {
+        version: 763,
+        original_host: rewrite_address("mc.example.com\0FML2\0", "backend.internal"),
+        port: 25566,
+        next_state: LOGIN,
+    };
+    let mut packet = Vec::new();
+    write_handshake(&mut packet, &handshake).await.unwrap();
+    let mut reader = &packet[..];
+    let length = reader.read_varint().await.unwrap();
+    assert_eq!(length as usize, reader.len());
+    assert_eq!(reader.read_varint().await.unwrap(), HANDSHAKE);
+    assert_eq!(reader.read_varint().await.unwrap(), 763);
+    assert_eq!(
+        reader.read_varstring().await.unwrap(),
+        "backend.internal\0FML2\0"
+    );
+    assert_eq!(reader.read_u16().await.unwrap(), 25566);
+    assert_eq!(reader.read_varint().await.unwrap(), LOGIN);
+}
@@ -0,0 +1,83 @@
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    pub handshake_timeouts: Counter,
+    pub connect_timeouts: Counter,
+    pub socks_timeouts: Counter,
+    pub idle_timeouts: Counter,
+    pub rejected_by_global_deny: Counter,
+    pub rejected_by_deny: Counter,
+    pub rejected_by_allow: Counter,
+    pub rate_limited: Counter,
+    pub concurrency_limited: Counter,
+    pub shed_by_listener: Counter,
+    pub shed_by_global: Counter,
+}
+
+lazy_static::lazy_static! {
+    pub static ref METRICS: Metrics = Metrics::default();
+}
+
+pub fn render() -> String {
+    let mut output = String::new();
+    let timeouts = [
+        ("handshake", &METRICS.handshake_timeouts),
+        ("connect", &METRICS.connect_timeouts),
+        ("socks", &METRICS.socks_timeouts),
+        ("idle", &METRICS.idle_timeouts),
+    ];
+    render_counter(&mut output, "rkp_timeouts_total", "phase", &timeouts);
+    let rejected = [
+        ("global_deny", &METRICS.rejected_by_global_deny),
+        ("deny", &METRICS.rejected_by_deny),
+        ("allow", &METRICS.rejected_by_allow),
+    ];
+    render_counter(&mut output, "rkp_rejected_clients_total", "list", &rejected);
+    let limited = [
+        ("connection_rate", &METRICS.rate_limited),
+        ("max_client_connections", &METRICS.concurrency_limited),
+    ];
+    render_counter(
+        &mut output,
+        "rkp_limited_connections_total",
+        "limit",
+        &limited,
+    );
+    let shed = [
+        ("listener", &METRICS.shed_by_listener),
+        ("global", &METRICS.shed_by_global),
+    ];
+    render_counter(&mut output, "rkp_shed_connections_total", "limit", &shed);
+    output
+}
+
+fn render_counter(output: &mut String, name: &str, label: &str, values: &[(&str, &Counter)]) {
+    let _ = writeln!(output, "# TYPE {} counter", name);
+    for (value, counter) in values {
+        let _ = writeln!(
+            output,
+            "{}{{{}=\"{}\"}} {}",
+            name,
+            label,
+            value,
+            counter.get()
+        );
+    }
+}
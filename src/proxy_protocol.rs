@@ -0,0 +1,92 @@
+use std::net::{IpAddr, SocketAddr};
+
+use crate::config::ProxyProtocol;
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+const V2_PROXY_COMMAND: u8 = 0x21;
+const V2_TCP4: u8 = 0x11;
+const V2_TCP6: u8 = 0x21;
+
+pub fn header(version: ProxyProtocol, source: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+    let (source_ip, destination_ip) = match (source.ip(), destination.ip()) {
+        (IpAddr::V4(source), IpAddr::V4(destination)) => {
+            (IpAddr::V4(source), IpAddr::V4(destination))
+        }
+        (source, destination) => (to_v6(source), to_v6(destination)),
+    };
+    match version {
+        ProxyProtocol::V1 => {
+            let family = match source_ip {
+                IpAddr::V4(_) => "TCP4",
+                IpAddr::V6(_) => "TCP6",
+            };
+            format!(
+                "PROXY {} {} {} {} {}\r\n",
+                family,
+                source_ip,
+                destination_ip,
+                source.port(),
+                destination.port()
+            )
+            .into_bytes()
+        }
+        ProxyProtocol::V2 => {
+            let mut addresses = Vec::new();
+            let family = match (source_ip, destination_ip) {
+                (IpAddr::V4(source), IpAddr::V4(destination)) => {
+                    addresses.extend_from_slice(&source.octets());
+                    addresses.extend_from_slice(&destination.octets());
+                    V2_TCP4
+                }
+                (IpAddr::V6(source), IpAddr::V6(destination)) => {
+                    addresses.extend_from_slice(&source.octets());
+                    addresses.extend_from_slice(&destination.octets());
+                    V2_TCP6
+                }
+                _ => unreachable!("address families are unified above"),
+            };
+            addresses.extend_from_slice(&source.port().to_be_bytes());
+            addresses.extend_from_slice(&destination.port().to_be_bytes());
+            let mut header = V2_SIGNATURE.to_vec();
+            header.push(V2_PROXY_COMMAND);
+            header.push(family);
+            header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+            header.extend_from_slice(&addresses);
+            header
+        }
+    }
+}
+
+fn to_v6(address: IpAddr) -> IpAddr {
+    match address {
+        IpAddr::V4(address) => IpAddr::V6(address.to_ipv6_mapped()),
+        address => address,
+    }
+}
+
+#[test]
+fn header_v1_test() {
+    let source = "192.0.2.1:51000".parse().unwrap();
+    let destination = "198.51.100.2:25565".parse().unwrap();
+    assert_eq!(
+        header(ProxyProtocol::V1, source, destination),
+        b"PROXY TCP4 192.0.2.1 198.51.100.2 51000 25565\r\n"
+    );
+    let source = "[2001:db8::1]:51000".parse().unwrap();
+    assert_eq!(
+        header(ProxyProtocol::V1, source, destination),
+        b"PROXY TCP6 2001:db8::1 ::ffff:198.51.100.2 51000 25565\r\n"
+    );
+}
+
+#[test]
+fn header_v2_test() {
+    let source = "192.0.2.1:51000".parse().unwrap();
+    let destination = "198.51.100.2:25565".parse().unwrap();
+    let header = header(ProxyProtocol::V2, source, destination);
+    assert_eq!(&header[..12], &V2_SIGNATURE);
+    assert_eq!(&header[12..16], &[0x21, 0x11, 0x00, 0x0C]);
+    assert_eq!(&header[16..20], &[192, 0, 2, 1]);
+    assert_eq!(&header[20..24], &[198, 51, 100, 2]);
+    assert_eq!(&header[24..], &[0xC7, 0x38, 0x63, 0xDD]);
+}
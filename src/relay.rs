@@ -0,0 +1,135 @@
+use std::{
+    io,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::Instant,
+};
+
+use crate::{
+    config::Limits,
+    limits::Throttled,
+    timeout::{self, Phase},
+};
+
+// `a` is the client side: `limits.upload` shapes what is read from it and
+// `limits.download` what is read from `b`.
+pub async fn copy_bidirectional<A, B>(
+    a: &mut A,
+    b: &mut B,
+    idle: Option<Duration>,
+    limits: &Limits,
+) -> anyhow::Result<(u64, u64)>
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut a = Throttled::new(a, limits.upload);
+    let mut b = Throttled::new(b, limits.download);
+    let (a, b) = (&mut a, &mut b);
+    let idle = match idle {
+        Some(idle) => idle,
+        None => return Ok(tokio::io::copy_bidirectional(a, b).await?),
+    };
+    let activity = Activity::new();
+    let mut a = Tracked {
+        inner: a,
+        activity: &activity,
+    };
+    let mut b = Tracked {
+        inner: b,
+        activity: &activity,
+    };
+    let copy = tokio::io::copy_bidirectional(&mut a, &mut b);
+    tokio::pin!(copy);
+    loop {
+        tokio::select! {
+            result = &mut copy => return Ok(result?),
+            _ = tokio::time::sleep_until(activity.last() + idle) => {
+                if activity.last().elapsed() >= idle {
+                    return Err(timeout::elapsed(Phase::Idle, idle));
+                }
+            }
+        }
+    }
+}
+
+pub struct Activity {
+    start: Instant,
+    last: AtomicU64,
+}
+
+impl Activity {
+    pub fn new() -> Self {
+        Activity {
+            start: Instant::now(),
+            last: AtomicU64::new(0),
+        }
+    }
+
+    pub fn touch(&self) {
+        let elapsed = self.start.elapsed().as_millis() as u64;
+        self.last.store(elapsed, Ordering::Relaxed);
+    }
+
+    pub fn last(&self) -> Instant {
+        self.start + Duration::from_millis(self.last.load(Ordering::Relaxed))
+    }
+}
+
+struct Tracked<'a, S> {
+    inner: &'a mut S,
+    activity: &'a Activity,
+}
+
+impl<'a, S: AsyncRead + Unpin> AsyncRead for Tracked<'a, S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut *self.inner).poll_read(cx, buf);
+        if buf.filled().len() > filled {
+            self.activity.touch();
+        }
+        result
+    }
+}
+
+impl<'a, S: AsyncWrite + Unpin> AsyncWrite for Tracked<'a, S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut *self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_shutdown(cx)
+    }
+}
+
+#[tokio::test]
+async fn idle_timeout_test() {
+    let (mut a, _a_peer) = tokio::io::duplex(64);
+    let (mut b, _b_peer) = tokio::io::duplex(64);
+    let result = copy_bidirectional(
+        &mut a,
+        &mut b,
+        Some(Duration::from_millis(50)),
+        &Limits::default(),
+    )
+    .await;
+    assert!(result.is_err());
+}
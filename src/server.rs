@@ -2,25 +2,26 @@ use std::pin::Pin;
 
 use futures::Future;
 
-use crate::{args, http_proxy, mc_proxy, tls_proxy};
+use crate::{
+    args, bedrock_proxy, connections, http_proxy, mc_proxy, socks_proxy, tls_proxy, tls_terminate,
+};
 
 pub async fn start() -> anyhow::Result<()> {
     let args = args::Args::get();
+    if let Some(max) = args.max_connections {
+        connections::set_global_limit(max);
+    }
     let tasks = args.bind.iter().map(|listener| match listener.kind {
         args::ListenerKind::HTTP => {
-            let f: Pin<Box<dyn Future<Output = Result<(), anyhow::Error>>>> = Box::pin(
-                http_proxy::actor(listener.addr.clone(), listener.chain.clone()),
-            );
+            let f: Pin<Box<dyn Future<Output = Result<(), anyhow::Error>>>> =
+                Box::pin(http_proxy::actor(listener.clone()));
             f
         }
-        args::ListenerKind::TLS => Box::pin(tls_proxy::actor(
-            listener.addr.clone(),
-            listener.chain.clone(),
-        )),
-        args::ListenerKind::MC => Box::pin(mc_proxy::actor(
-            listener.addr.clone(),
-            listener.chain.clone(),
-        )),
+        args::ListenerKind::TLS => Box::pin(tls_proxy::actor(listener.clone())),
+        args::ListenerKind::TLSTerminate => Box::pin(tls_terminate::actor(listener.clone())),
+        args::ListenerKind::MC => Box::pin(mc_proxy::actor(listener.clone())),
+        args::ListenerKind::Bedrock => Box::pin(bedrock_proxy::actor(listener.clone())),
+        args::ListenerKind::SOCKS5 => Box::pin(socks_proxy::actor(listener.clone())),
     });
     futures::future::try_join_all(tasks).await?;
     Ok(())
@@ -0,0 +1,117 @@
+use std::{future::Future, io, time::Duration};
+
+use tokio::task::JoinHandle;
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+
+lazy_static::lazy_static! {
+    static ref TOKEN: CancellationToken = CancellationToken::new();
+    static ref TRACKER: TaskTracker = TaskTracker::new();
+}
+
+// Listeners stop accepting once shutdown is triggered, while the connections
+// they already handed off keep running until they finish or the drain
+// deadline passes.
+pub fn trigger() {
+    if !TOKEN.is_cancelled() {
+        log::info!("shutting down; stop accepting new connections");
+        TOKEN.cancel();
+    }
+}
+
+pub fn is_draining() -> bool {
+    TOKEN.is_cancelled()
+}
+
+pub async fn cancelled() {
+    TOKEN.cancelled().await
+}
+
+// Spawns a connection task that shutdown waits for.
+pub fn spawn<F>(task: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    TRACKER.spawn(task)
+}
+
+// The first SIGTERM or SIGINT, or Ctrl+C outside Unix, starts a graceful
+// shutdown, a second one exits right away.
+pub async fn watch_signals() {
+    let mut signals = match Signals::new() {
+        Ok(signals) => signals,
+        Err(error) => {
+            log::error!("failed to install signal handlers: {}", error);
+            return;
+        }
+    };
+    loop {
+        if let Err(error) = signals.recv().await {
+            log::error!("failed to wait for shutdown signal: {}", error);
+            return;
+        }
+        if is_draining() {
+            log::warn!("received second shutdown signal; exit immediately");
+            log::logger().flush();
+            std::process::exit(1);
+        }
+        trigger();
+    }
+}
+
+#[cfg(unix)]
+struct Signals {
+    terminate: tokio::signal::unix::Signal,
+    interrupt: tokio::signal::unix::Signal,
+}
+
+#[cfg(unix)]
+impl Signals {
+    fn new() -> io::Result<Self> {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        Ok(Signals {
+            terminate: signal(SignalKind::terminate())?,
+            interrupt: signal(SignalKind::interrupt())?,
+        })
+    }
+
+    async fn recv(&mut self) -> io::Result<()> {
+        tokio::select! {
+            _ = self.terminate.recv() => {}
+            _ = self.interrupt.recv() => {}
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(unix))]
+struct Signals;
+
+#[cfg(not(unix))]
+impl Signals {
+    fn new() -> io::Result<Self> {
+        Ok(Signals)
+    }
+
+    async fn recv(&mut self) -> io::Result<()> {
+        tokio::signal::ctrl_c().await
+    }
+}
+
+// Waits for the connections still open to finish, giving up after
+// `deadline`. Whatever is left is dropped when the runtime shuts down.
+pub async fn drain(deadline: Duration) {
+    TRACKER.close();
+    if !TRACKER.is_empty() {
+        log::info!("draining {} connection tasks", TRACKER.len());
+    }
+    match tokio::time::timeout(deadline, TRACKER.wait()).await {
+        Ok(()) => log::info!("all connections drained"),
+        Err(_) => log::warn!(
+            "drain deadline of {:?} passed; close {} remaining connection tasks",
+            deadline,
+            TRACKER.len()
+        ),
+    }
+}
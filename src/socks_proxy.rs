@@ -0,0 +1,248 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use anyhow::Result;
+use futures::FutureExt;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::{
+    args, auth, chain, config, connections, destination, limits, relay,
+    timeout::{self, Phase},
+};
+
+const VERSION: u8 = 0x05;
+const METHOD_NONE: u8 = 0x00;
+const METHOD_PASSWORD: u8 = 0x02;
+const METHOD_UNACCEPTABLE: u8 = 0xFF;
+// RFC 1929 username/password negotiation
+const PASSWORD_VERSION: u8 = 0x01;
+const PASSWORD_SUCCEEDED: u8 = 0x00;
+const PASSWORD_FAILED: u8 = 0x01;
+const COMMAND_CONNECT: u8 = 0x01;
+const ADDRESS_IPV4: u8 = 0x01;
+const ADDRESS_DOMAIN: u8 = 0x03;
+const ADDRESS_IPV6: u8 = 0x04;
+const REPLY_SUCCEEDED: u8 = 0x00;
+const REPLY_FAILURE: u8 = 0x01;
+const REPLY_NOT_ALLOWED: u8 = 0x02;
+const REPLY_COMMAND_UNSUPPORTED: u8 = 0x07;
+const REPLY_ADDRESS_UNSUPPORTED: u8 = 0x08;
+
+pub async fn actor(listener: args::Listener) -> Result<()> {
+    connections::serve(
+        listener,
+        "SOCKS5",
+        |stream, listener| async move { proxy(stream, &listener).await }.boxed(),
+        Some(|stream, listener| async move { refuse(stream, &listener).await }.boxed()),
+    )
+    .await
+}
+
+async fn proxy(mut stream: TcpStream, listener: &args::Listener) -> Result<()> {
+    let config = config::get_current_config().await;
+    let handshake_timeout = listener.timeouts.or(config.timeouts).handshake;
+    let (user, (host, port)) = timeout::run(Phase::Handshake, handshake_timeout, async {
+        let user = negotiate(&mut stream, listener.auth).await?;
+        let target = read_request(&mut stream).await?;
+        Ok((user, target))
+    })
+    .await?;
+    let (user, start) = match user {
+        Some((name, user)) => (Some(name), user.chain.unwrap_or(listener.chain.clone())),
+        None => (None, listener.chain.clone()),
+    };
+    let context = chain::Context {
+        host,
+        port,
+        user,
+        client: Some(stream.peer_addr()?),
+        local: Some(stream.local_addr()?),
+        ..Default::default()
+    };
+    log::debug!("SOCKS5 connect to \"{}:{}\"", context.host, context.port);
+    let route = chain::route(&context, &start, listener).await;
+    let _permit = match limits::admit(&route, &context) {
+        Ok(permit) => permit,
+        Err(error) => {
+            stream.write_all(&reply(REPLY_NOT_ALLOWED)).await?;
+            return Err(error);
+        }
+    };
+    let mut proxy = match route.connect(&context).await {
+        Ok(proxy) => proxy,
+        Err(error) => {
+            stream.write_all(&reply(connect_failure(&error))).await?;
+            return Err(error);
+        }
+    };
+    stream.write_all(&reply(REPLY_SUCCEEDED)).await?;
+    relay::copy_bidirectional(&mut stream, &mut proxy, route.timeouts.idle, &route.limits).await?;
+    Ok(())
+}
+
+// Rejected clients are told so with "connection not allowed by ruleset"
+// when they get as far as sending a request.
+async fn refuse(mut stream: TcpStream, listener: &args::Listener) -> Result<()> {
+    let config = config::get_current_config().await;
+    let handshake_timeout = listener.timeouts.or(config.timeouts).handshake;
+    timeout::run(Phase::Handshake, handshake_timeout, async {
+        let methods = read_methods(&mut stream).await?;
+        if !methods.contains(&METHOD_NONE) {
+            stream.write_all(&[VERSION, METHOD_UNACCEPTABLE]).await?;
+            return Ok(());
+        }
+        stream.write_all(&[VERSION, METHOD_NONE]).await?;
+        read_request(&mut stream).await?;
+        stream.write_all(&reply(REPLY_NOT_ALLOWED)).await?;
+        Ok(())
+    })
+    .await
+}
+
+async fn read_methods<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Vec<u8>> {
+    let version = stream.read_u8().await?;
+    if version != VERSION {
+        return Err(anyhow::anyhow!("unsupported SOCKS version {}", version));
+    }
+    let mut methods = vec![0; stream.read_u8().await? as usize];
+    stream.read_exact(&mut methods).await?;
+    Ok(methods)
+}
+
+// Picks the authentication method and, when required, checks the client's
+// username and password.
+async fn negotiate<S>(stream: &mut S, auth: bool) -> Result<Option<(String, config::User)>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let methods = read_methods(stream).await?;
+    let method = if auth { METHOD_PASSWORD } else { METHOD_NONE };
+    if !methods.contains(&method) {
+        stream.write_all(&[VERSION, METHOD_UNACCEPTABLE]).await?;
+        return Err(anyhow::anyhow!("no acceptable authentication method"));
+    }
+    stream.write_all(&[VERSION, method]).await?;
+    if !auth {
+        return Ok(None);
+    }
+    let version = stream.read_u8().await?;
+    if version != PASSWORD_VERSION {
+        return Err(anyhow::anyhow!(
+            "unsupported authentication version {}",
+            version
+        ));
+    }
+    let username = read_string(stream).await?;
+    let password = read_string(stream).await?;
+    match auth::authenticate(&username, &password).await {
+        Some(user) => {
+            stream
+                .write_all(&[PASSWORD_VERSION, PASSWORD_SUCCEEDED])
+                .await?;
+            Ok(Some((username, user)))
+        }
+        None => {
+            stream
+                .write_all(&[PASSWORD_VERSION, PASSWORD_FAILED])
+                .await?;
+            Err(anyhow::anyhow!(
+                "authentication failed for \"{}\"",
+                username
+            ))
+        }
+    }
+}
+
+async fn read_request<S>(stream: &mut S) -> Result<(String, u16)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut header = [0; 4];
+    stream.read_exact(&mut header).await?;
+    let [version, command, _, address_type] = header;
+    if version != VERSION {
+        return Err(anyhow::anyhow!("unsupported SOCKS version {}", version));
+    }
+    let host = match address_type {
+        ADDRESS_IPV4 => {
+            let mut octets = [0; 4];
+            stream.read_exact(&mut octets).await?;
+            Ipv4Addr::from(octets).to_string()
+        }
+        ADDRESS_IPV6 => {
+            let mut octets = [0; 16];
+            stream.read_exact(&mut octets).await?;
+            Ipv6Addr::from(octets).to_string()
+        }
+        ADDRESS_DOMAIN => read_string(stream).await?,
+        _ => {
+            stream.write_all(&reply(REPLY_ADDRESS_UNSUPPORTED)).await?;
+            return Err(anyhow::anyhow!("unsupported address type {}", address_type));
+        }
+    };
+    let port = stream.read_u16().await?;
+    if command != COMMAND_CONNECT {
+        stream.write_all(&reply(REPLY_COMMAND_UNSUPPORTED)).await?;
+        return Err(anyhow::anyhow!("unsupported command {}", command));
+    }
+    Ok((host, port))
+}
+
+async fn read_string<S: AsyncRead + Unpin>(stream: &mut S) -> Result<String> {
+    let mut bytes = vec![0; stream.read_u8().await? as usize];
+    stream.read_exact(&mut bytes).await?;
+    Ok(String::from_utf8(bytes)?)
+}
+
+// Destinations refused by the policy get "connection not allowed by ruleset",
+// anything else a general failure.
+fn connect_failure(error: &anyhow::Error) -> u8 {
+    if destination::is_not_permitted(error) {
+        REPLY_NOT_ALLOWED
+    } else {
+        REPLY_FAILURE
+    }
+}
+
+// Clients ignore the bound address for CONNECT, so it is left unspecified.
+fn reply(code: u8) -> [u8; 10] {
+    [VERSION, code, 0x00, ADDRESS_IPV4, 0, 0, 0, 0, 0, 0]
+}
+
+#[tokio::test]
+async fn handshake_test() {
+    let (mut client, mut server) = tokio::io::duplex(64);
+    client.write_all(&[VERSION, 1, METHOD_NONE]).await.unwrap();
+    client
+        .write_all(&[VERSION, COMMAND_CONNECT, 0, ADDRESS_DOMAIN, 11])
+        .await
+        .unwrap();
+    client.write_all(b"example.com\x01\xbb").await.unwrap();
+    assert!(negotiate(&mut server, false).await.unwrap().is_none());
+    assert_eq!(
+        read_request(&mut server).await.unwrap(),
+        ("example.com".to_owned(), 443)
+    );
+    let mut method = [0; 2];
+    client.read_exact(&mut method).await.unwrap();
+    assert_eq!(method, [VERSION, METHOD_NONE]);
+
+    let (mut client, mut server) = tokio::io::duplex(64);
+    client.write_all(&[VERSION, 1, METHOD_NONE]).await.unwrap();
+    assert!(negotiate(&mut server, true).await.is_err());
+    client.read_exact(&mut method).await.unwrap();
+    assert_eq!(method, [VERSION, METHOD_UNACCEPTABLE]);
+}
+
+#[tokio::test]
+async fn connect_failure_test() {
+    let error =
+        crate::happy_eyeballs::connect("127.0.0.1", 9, config::AddressFamily::PreferV4, true)
+            .await
+            .unwrap_err();
+    assert_eq!(connect_failure(&error), REPLY_NOT_ALLOWED);
+    let error = anyhow::anyhow!("connection refused");
+    assert_eq!(connect_failure(&error), REPLY_FAILURE);
+}
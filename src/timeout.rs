@@ -0,0 +1,53 @@
+use std::{fmt::Display, future::Future, time::Duration};
+
+use crate::metrics::{Counter, METRICS};
+
+#[derive(Debug, Clone, Copy)]
+pub enum Phase {
+    Handshake,
+    Connect,
+    Socks,
+    Idle,
+}
+
+impl Phase {
+    fn counter(self) -> &'static Counter {
+        match self {
+            Phase::Handshake => &METRICS.handshake_timeouts,
+            Phase::Connect => &METRICS.connect_timeouts,
+            Phase::Socks => &METRICS.socks_timeouts,
+            Phase::Idle => &METRICS.idle_timeouts,
+        }
+    }
+}
+
+impl Display for Phase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Phase::Handshake => "handshake",
+            Phase::Connect => "connect",
+            Phase::Socks => "SOCKS negotiation",
+            Phase::Idle => "idle",
+        };
+        f.write_str(name)
+    }
+}
+
+pub fn elapsed(phase: Phase, duration: Duration) -> anyhow::Error {
+    phase.counter().inc();
+    log::info!("{} timeout elapsed ({:?})", phase, duration);
+    anyhow::anyhow!("{} timed out after {:?}", phase, duration)
+}
+
+pub async fn run<F, T>(phase: Phase, duration: Option<Duration>, future: F) -> anyhow::Result<T>
+where
+    F: Future<Output = anyhow::Result<T>>,
+{
+    match duration {
+        Some(duration) => match tokio::time::timeout(duration, future).await {
+            Ok(result) => result,
+            Err(_) => Err(elapsed(phase, duration)),
+        },
+        None => future.await,
+    }
+}
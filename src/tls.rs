@@ -0,0 +1,316 @@
+use std::{
+    collections::HashMap,
+    io::BufReader,
+    path::Path,
+    sync::{Arc, Mutex, RwLock},
+    time::SystemTime,
+};
+
+use anyhow::{Context as _, Result};
+use sha2::{Digest, Sha256};
+use tokio::fs;
+use tokio_rustls::{
+    client,
+    rustls::{
+        self,
+        client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier},
+        server::{ClientHello, ResolvesServerCert},
+        sign::{self, CertifiedKey},
+        Certificate, ClientConfig, OwnedTrustAnchor, PrivateKey, RootCertStore, ServerConfig,
+        ServerName,
+    },
+    TlsAcceptor, TlsConnector,
+};
+use wildmatch::WildMatch;
+
+use crate::config;
+
+#[derive(Default)]
+pub struct CertStore {
+    settings: config::Tls,
+    entries: Vec<(Vec<WildMatch>, Arc<CertifiedKey>)>,
+}
+
+impl CertStore {
+    async fn load(settings: &config::Tls) -> Result<Self> {
+        let mut entries = Vec::new();
+        for files in &settings.certificates {
+            let key = load_certified_key(files).await?;
+            let patterns = files
+                .server_names
+                .iter()
+                .map(|name| WildMatch::new(&name.to_lowercase()))
+                .collect();
+            entries.push((patterns, key));
+        }
+        Ok(CertStore {
+            settings: settings.clone(),
+            entries,
+        })
+    }
+
+    // Certificates are tried in configuration order, so more specific names
+    // should be listed before wildcards.
+    pub fn find(&self, server_name: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        let server_name = server_name.unwrap_or_default().to_lowercase();
+        self.entries
+            .iter()
+            .find(|(patterns, _)| patterns.iter().any(|it| it.matches(&server_name)))
+            .map(|(_, key)| key.clone())
+    }
+}
+
+async fn load_certified_key(files: &config::CertificateFiles) -> Result<Arc<CertifiedKey>> {
+    let certificates = read_certificates(&files.certificate).await?;
+    let key = read_private_key(&files.key).await?;
+    let key = sign::any_supported_type(&key)
+        .with_context(|| format!("unsupported private key in \"{}\"", files.key.display()))?;
+    log::info!(
+        "loaded certificate from \"{}\" for {:?}",
+        files.certificate.display(),
+        files.server_names
+    );
+    Ok(Arc::new(CertifiedKey::new(certificates, key)))
+}
+
+async fn read_certificates(path: &Path) -> Result<Vec<Certificate>> {
+    let certificates: Vec<_> = read_pem(path)
+        .await?
+        .into_iter()
+        .filter_map(|item| match item {
+            rustls_pemfile::Item::X509Certificate(der) => Some(Certificate(der)),
+            _ => None,
+        })
+        .collect();
+    if certificates.is_empty() {
+        return Err(anyhow::anyhow!(
+            "no certificates found in \"{}\"",
+            path.display()
+        ));
+    }
+    Ok(certificates)
+}
+
+async fn read_private_key(path: &Path) -> Result<PrivateKey> {
+    read_pem(path)
+        .await?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::RSAKey(der)
+            | rustls_pemfile::Item::PKCS8Key(der)
+            | rustls_pemfile::Item::ECKey(der) => Some(PrivateKey(der)),
+            _ => None,
+        })
+        .ok_or_else(|| anyhow::anyhow!("no private key found in \"{}\"", path.display()))
+}
+
+async fn read_pem(path: &Path) -> Result<Vec<rustls_pemfile::Item>> {
+    let contents = fs::read(path)
+        .await
+        .with_context(|| format!("failed to read \"{}\"", path.display()))?;
+    rustls_pemfile::read_all(&mut BufReader::new(&contents[..]))
+        .with_context(|| format!("failed to parse \"{}\"", path.display()))
+}
+
+// The store is consulted from inside the rustls handshake, which is
+// synchronous, hence the std lock rather than the tokio one.
+lazy_static::lazy_static! {
+    static ref CERT_STORE: RwLock<Arc<CertStore>> = RwLock::new(Arc::new(CertStore::default()));
+    static ref ACCEPTOR: TlsAcceptor = TlsAcceptor::from(Arc::new(
+        ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(StoreResolver))
+    ));
+    static ref CLIENT_CONFIGS: Mutex<HashMap<config::TlsClient, Arc<ClientConfig>>> =
+        Mutex::new(HashMap::new());
+}
+
+pub fn get_cert_store() -> Arc<CertStore> {
+    CERT_STORE.read().unwrap().clone()
+}
+
+pub async fn install(settings: &config::Tls) {
+    // Client settings refer to files as well, so rebuild those lazily.
+    CLIENT_CONFIGS.lock().unwrap().clear();
+    if &get_cert_store().settings == settings {
+        return;
+    }
+    match CertStore::load(settings).await {
+        Ok(store) => *CERT_STORE.write().unwrap() = Arc::new(store),
+        Err(error) => log::error!("failed to load TLS certificates: {:#}", error),
+    }
+}
+
+struct StoreResolver;
+
+impl ResolvesServerCert for StoreResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let key = get_cert_store().find(client_hello.server_name());
+        if key.is_none() {
+            log::debug!(
+                "no certificate configured for {:?}",
+                client_hello.server_name()
+            );
+        }
+        key
+    }
+}
+
+pub fn acceptor() -> &'static TlsAcceptor {
+    &ACCEPTOR
+}
+
+pub async fn connect<S>(
+    stream: S,
+    server_name: &str,
+    settings: &config::TlsClient,
+) -> Result<client::TlsStream<S>>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    let server_name = settings.sni.as_deref().unwrap_or(server_name);
+    let server_name = ServerName::try_from(server_name)
+        .with_context(|| format!("invalid TLS server name \"{}\"", server_name))?;
+    let connector = TlsConnector::from(client_config(settings).await?);
+    Ok(connector.connect(server_name, stream).await?)
+}
+
+async fn client_config(settings: &config::TlsClient) -> Result<Arc<ClientConfig>> {
+    if let Some(config) = CLIENT_CONFIGS.lock().unwrap().get(settings) {
+        return Ok(config.clone());
+    }
+    let roots = match &settings.ca {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for certificate in read_certificates(path).await? {
+                roots
+                    .add(&certificate)
+                    .with_context(|| format!("bad CA certificate in \"{}\"", path.display()))?;
+            }
+            roots
+        }
+        None => webpki_root_store(),
+    };
+    let verifier: Arc<dyn ServerCertVerifier> = if settings.pins.is_empty() {
+        Arc::new(WebPkiVerifier::new(roots, None))
+    } else {
+        Arc::new(PinnedVerifier {
+            inner: WebPkiVerifier::new(roots, None),
+            pins: settings.pins.iter().map(|pin| pin.to_lowercase()).collect(),
+        })
+    };
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(verifier);
+    let config = match (&settings.certificate, &settings.key) {
+        (Some(certificate), Some(key)) => builder.with_client_auth_cert(
+            read_certificates(certificate).await?,
+            read_private_key(key).await?,
+        )?,
+        (None, None) => builder.with_no_client_auth(),
+        _ => {
+            return Err(anyhow::anyhow!(
+                "client certificate and key must be configured together"
+            ))
+        }
+    };
+    let config = Arc::new(config);
+    CLIENT_CONFIGS
+        .lock()
+        .unwrap()
+        .insert(settings.clone(), config.clone());
+    Ok(config)
+}
+
+// Pins are hex-encoded SHA-256 digests of the server's end-entity
+// certificate, checked on top of the usual chain validation.
+struct PinnedVerifier {
+    inner: WebPkiVerifier,
+    pins: Vec<String>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            scts,
+            ocsp_response,
+            now,
+        )?;
+        let digest: String = Sha256::digest(&end_entity.0)
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        if self.pins.contains(&digest) {
+            Ok(verified)
+        } else {
+            Err(rustls::Error::General(format!(
+                "certificate {} does not match any pin",
+                digest
+            )))
+        }
+    }
+}
+
+fn webpki_root_store() -> RootCertStore {
+    let mut roots = RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            anchor.subject,
+            anchor.spki,
+            anchor.name_constraints,
+        )
+    }));
+    roots
+}
+
+#[cfg(test)]
+pub fn test_settings() -> config::Tls {
+    let testdata = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata");
+    config::Tls {
+        certificates: vec![
+            config::CertificateFiles {
+                server_names: vec![String::from("Example.com")],
+                certificate: testdata.join("example.pem"),
+                key: testdata.join("example.key"),
+            },
+            config::CertificateFiles {
+                server_names: vec![String::from("*.example.com"), String::from("")],
+                certificate: testdata.join("wildcard.pem"),
+                key: testdata.join("wildcard.key"),
+            },
+        ],
+    }
+}
+
+#[tokio::test]
+async fn cert_store_find_test() {
+    let store = CertStore::load(&test_settings()).await.unwrap();
+    let example = &store.entries[0].1;
+    let wildcard = &store.entries[1].1;
+    let find = |server_name| store.find(server_name).map(|key| key.cert[0].clone());
+    assert_eq!(find(Some("example.com")), Some(example.cert[0].clone()));
+    assert_eq!(find(Some("EXAMPLE.COM")), Some(example.cert[0].clone()));
+    assert_eq!(
+        find(Some("www.example.com")),
+        Some(wildcard.cert[0].clone())
+    );
+    assert_eq!(find(None), Some(wildcard.cert[0].clone()));
+    assert_eq!(find(Some("example.org")), None);
+    assert!(CertStore::default().find(Some("example.com")).is_none());
+
+    let mut settings = test_settings();
+    settings.certificates[0].key = settings.certificates[0].certificate.clone();
+    assert!(CertStore::load(&settings).await.is_err());
+}
@@ -1,141 +1,188 @@
-use std::{fmt::Display, net::SocketAddr, str::from_utf8};
+use std::net::SocketAddr;
 
-use bytes::{Buf, Bytes, BytesMut};
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::{TcpListener, TcpStream},
+use futures::FutureExt;
+use tokio::{io::AsyncWriteExt, net::TcpStream};
+
+use crate::{
+    args, chain, client_hello, config, connections, limits, relay,
+    timeout::{self, Phase},
 };
 
-use crate::chain;
+pub async fn actor(listener: args::Listener) -> anyhow::Result<()> {
+    connections::serve(
+        listener,
+        "TLS",
+        |stream, listener| async move { proxy(stream, &listener).await }.boxed(),
+        None,
+    )
+    .await
+}
 
-pub async fn actor(address: SocketAddr, chain: String) -> anyhow::Result<()> {
-    let listener = TcpListener::bind(&address).await?;
+async fn proxy(mut stream: TcpStream, listener: &args::Listener) -> anyhow::Result<()> {
+    let config = config::get_current_config().await;
+    let handshake_timeout = listener.timeouts.or(config.timeouts).handshake;
+    let (client_hello, mut buffered) = timeout::run(Phase::Handshake, handshake_timeout, async {
+        Ok(client_hello::read(&mut stream).await?)
+    })
+    .await?;
+    let context = context(&stream, client_hello, listener)?;
+    log::debug!("TLS connect to \"{}:{}\"", context.host, context.port);
+    let route = chain::route(&context, &listener.chain, listener).await;
+    let _permit = limits::admit(&route, &context)?;
+    let mut proxy = route.connect(&context).await?;
+    proxy.write_all_buf(&mut buffered).await?;
+    proxy.flush().await?;
+    relay::copy_bidirectional(&mut stream, &mut proxy, route.timeouts.idle, &route.limits).await?;
+    Ok(())
+}
 
-    loop {
-        let chain = chain.clone();
-        let (stream, client_addr) = listener.accept().await?;
-        tokio::spawn(async move {
-            log::debug!("TLS connection accepted: {}", &client_addr);
-            if let Err(error) = proxy(stream, chain).await {
-                log::debug!("an error occurred in TLS connection; error = {}", error);
-            };
-        });
+pub fn context(
+    stream: &TcpStream,
+    client_hello: client_hello::ClientHello,
+    listener: &args::Listener,
+) -> anyhow::Result<chain::Context> {
+    let original_destination = if listener.transparent {
+        Some(original_destination(stream)?)
+    } else {
+        None
+    };
+    Ok(chain::Context {
+        client: stream.peer_addr().ok(),
+        local: stream.local_addr().ok(),
+        ..target(client_hello, listener, original_destination)?
+    })
+}
+
+fn target(
+    client_hello: client_hello::ClientHello,
+    listener: &args::Listener,
+    original_destination: Option<SocketAddr>,
+) -> anyhow::Result<chain::Context> {
+    let port = listener
+        .port
+        .or(original_destination.map(|address| address.port()))
+        .unwrap_or(listener.addr.port());
+    match (&client_hello.server_name, &listener.fallback) {
+        (Some(server_name), _) => Ok(chain::Context {
+            host: server_name.clone(),
+            port,
+            tls: Some(client_hello),
+            ..Default::default()
+        }),
+        (None, Some(fallback)) => {
+            let (host, port) = chain::split_address(fallback)?;
+            Ok(chain::Context {
+                host: host.to_owned(),
+                port,
+                tls: Some(client_hello),
+                trusted: true,
+                ..Default::default()
+            })
+        }
+        (None, None) => match original_destination {
+            Some(address) => Ok(chain::Context {
+                host: address.ip().to_string(),
+                port: address.port(),
+                tls: Some(client_hello),
+                ..Default::default()
+            }),
+            None => Err(anyhow::anyhow!("no server_name extension found; drop")),
+        },
     }
 }
 
-trait BufferExt {
-    fn get_u24(&mut self) -> u32;
+#[cfg(target_os = "linux")]
+fn original_destination(stream: &TcpStream) -> std::io::Result<SocketAddr> {
+    let socket = socket2::SockRef::from(stream);
+    let address = match stream.local_addr()? {
+        SocketAddr::V4(_) => socket.original_dst_v4()?,
+        SocketAddr::V6(_) => socket.original_dst_v6()?,
+    };
+    address.as_socket().ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "original destination is not an IP address",
+        )
+    })
 }
 
-impl BufferExt for Bytes {
-    fn get_u24(&mut self) -> u32 {
-        let bytes: [u8; 4] = [0, self.get_u8(), self.get_u8(), self.get_u8()];
-        u32::from_be_bytes(bytes)
-    }
+#[cfg(not(target_os = "linux"))]
+fn original_destination(_stream: &TcpStream) -> std::io::Result<SocketAddr> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "transparent mode is supported on Linux only",
+    ))
 }
 
-const CONTENT_TYPE_HANDSHAKE: u8 = 22;
-const HANDSHAKE_TYPE_CLIENT_HELLO: u8 = 1;
-const EXTENSION_SERVER_NAME: u16 = 0;
-const EXTENSION_SERVER_NAME_TYPE_HOSTNAME: u8 = 0;
-
-async fn proxy(mut stream: TcpStream, chain: String) -> anyhow::Result<()> {
-    let content_type = stream.read_u8().await?;
-    validate(CONTENT_TYPE_HANDSHAKE, content_type, "content type")?;
-    let version = stream.read_u16().await?;
-    let message_length = stream.read_u16().await?;
-    let mut buffer = BytesMut::with_capacity(message_length.into());
-    stream.read_buf(&mut buffer).await?;
-    let mut buff_a = buffer.freeze();
-    let mut buff_b = buff_a.clone();
-    let handshake_type = buff_a.get_u8();
-    validate(
-        HANDSHAKE_TYPE_CLIENT_HELLO,
-        handshake_type,
-        "handshake type",
-    )?;
-    let hello_length = buff_a.get_u24();
-    validate(
-        (message_length - 4).into(),
-        hello_length,
-        "handshake message length",
-    )?;
-    buff_a.advance(2); // skip handshake version
-    buff_a.advance(32); // skip random
-    let session_id_length = buff_a.get_u8();
-    buff_a.advance(session_id_length.into()); // skip session id
-    let cipher_suits_length = buff_a.get_u16();
-    buff_a.advance(cipher_suits_length.into());
-    let compression_methods_length = buff_a.get_u8();
-    buff_a.advance(compression_methods_length.into());
-    let extension_methods_length: u16 = buff_a.get_u16();
-    let mut remains = extension_methods_length;
-    let server_name = loop {
-        let extension_type = buff_a.get_u16();
-        let extension_length: u16 = buff_a.get_u16();
-        if extension_type == EXTENSION_SERVER_NAME {
-            let server_name_list_length = buff_a.get_u16();
-            validate(
-                extension_length - 2,
-                server_name_list_length,
-                "extension server_name list length",
-            )?;
-            if server_name_list_length == 0 {
-                continue;
-            }
-            let server_name_type = buff_a.get_u8();
-            if server_name_type == EXTENSION_SERVER_NAME_TYPE_HOSTNAME {
-                let server_name_length = buff_a.get_u16();
-                validate(
-                    server_name_list_length - 3,
-                    server_name_length,
-                    "extension server_name server name length",
-                )?;
-                let server_name_length: usize = server_name_length.into();
-                let server_name = &buff_a.chunk()[0..server_name_length];
-                break from_utf8(server_name).unwrap();
-            }
-        } else {
-            buff_a.advance(extension_length.into());
-        }
-        log::debug!(
-            "extension_length = {}, remains = {}",
-            extension_length,
-            remains
-        );
-        remains -= 4 + extension_length;
-        if remains == 0 {
-            return Err(anyhow::anyhow!("no server_name extension found; drop"));
-        };
+#[test]
+fn port_override_test() {
+    let client_hello = client_hello::ClientHello {
+        server_name: Some(String::from("example.com")),
+        ..Default::default()
     };
-    log::debug!("TLS connect to \"{}\"", server_name);
-    let context = chain::Context {
-        host: server_name.to_owned(),
-        port: 443,
-        address: format!("{}:{}", server_name, 443),
+    let listener: args::Listener = "kind=tls,addr=0.0.0.0:8443,chain=tls".parse().unwrap();
+    let context = target(client_hello.clone(), &listener, None).unwrap();
+    assert_eq!((context.host.as_str(), context.port), ("example.com", 8443));
+    assert!(context.tls.is_some());
+
+    let listener: args::Listener = "kind=tls,addr=0.0.0.0:8443,chain=tls,port=443"
+        .parse()
+        .unwrap();
+    let context = target(client_hello, &listener, None).unwrap();
+    assert_eq!((context.host.as_str(), context.port), ("example.com", 443));
+}
+
+#[test]
+fn fallback_test() {
+    let listener: args::Listener = "kind=tls,chain=tls,port=443,fallback=default.example.com:8443"
+        .parse()
+        .unwrap();
+    let context = target(client_hello::ClientHello::default(), &listener, None).unwrap();
+    assert_eq!(
+        (context.host.as_str(), context.port),
+        ("default.example.com", 8443)
+    );
+    assert!(context.trusted);
+
+    let client_hello = client_hello::ClientHello {
+        server_name: Some(String::from("example.com")),
+        ..Default::default()
     };
-    let mut proxy = chain::connect(context, chain.to_owned()).await?;
-    proxy.write_u8(content_type).await?;
-    proxy.write_u16(version).await?;
-    proxy.write_u16(message_length).await?;
-    proxy.write_all_buf(&mut buff_b).await?;
-    proxy.flush().await?;
-    tokio::io::copy_bidirectional(&mut stream, &mut proxy).await?;
-    Ok(())
+    let context = target(client_hello, &listener, None).unwrap();
+    assert_eq!((context.host.as_str(), context.port), ("example.com", 443));
+    assert!(!context.trusted);
+
+    let listener: args::Listener = "kind=tls,chain=tls".parse().unwrap();
+    assert!(target(client_hello::ClientHello::default(), &listener, None).is_err());
+    let listener: args::Listener = "kind=tls,chain=tls,fallback=example.com".parse().unwrap();
+    assert!(target(client_hello::ClientHello::default(), &listener, None).is_err());
 }
 
-fn validate<T>(expect: T, actual: T, field_name: &str) -> anyhow::Result<()>
-where
-    T: Eq + Display,
-{
-    if expect == actual {
-        Ok(())
-    } else {
-        let message = format!(
-            "wrong TLS field \"{}\" value ({} expected, got {})",
-            field_name, expect, actual
-        );
-        log::debug!("{}", &message);
-        Err(anyhow::Error::msg(message))
-    }
+#[test]
+fn transparent_test() {
+    let listener: args::Listener = "kind=tls,chain=tls,transparent=true".parse().unwrap();
+    let original_destination = Some("203.0.113.10:8443".parse().unwrap());
+    let client_hello = client_hello::ClientHello {
+        server_name: Some(String::from("example.com")),
+        ..Default::default()
+    };
+    let context = target(client_hello.clone(), &listener, original_destination).unwrap();
+    assert_eq!((context.host.as_str(), context.port), ("example.com", 8443));
+
+    let context = target(
+        client_hello::ClientHello::default(),
+        &listener,
+        original_destination,
+    )
+    .unwrap();
+    assert_eq!(
+        (context.host.as_str(), context.port),
+        ("203.0.113.10", 8443)
+    );
+
+    let listener: args::Listener = "kind=tls,chain=tls,transparent=true,port=443"
+        .parse()
+        .unwrap();
+    let context = target(client_hello, &listener, original_destination).unwrap();
+    assert_eq!((context.host.as_str(), context.port), ("example.com", 443));
 }
@@ -0,0 +1,119 @@
+use futures::FutureExt;
+use tokio::net::TcpStream;
+use tokio_rustls::server;
+
+use crate::{
+    args, chain, client_hello, config, connections, limits, relay,
+    stream::Prefixed,
+    timeout::{self, Phase},
+    tls, tls_proxy,
+};
+
+pub async fn actor(listener: args::Listener) -> anyhow::Result<()> {
+    connections::serve(
+        listener,
+        "TLS termination",
+        |stream, listener| async move { proxy(stream, &listener).await }.boxed(),
+        None,
+    )
+    .await
+}
+
+async fn proxy(stream: TcpStream, listener: &args::Listener) -> anyhow::Result<()> {
+    let config = config::get_current_config().await;
+    let handshake_timeout = listener.timeouts.or(config.timeouts).handshake;
+    let (context, mut stream) = timeout::run(
+        Phase::Handshake,
+        handshake_timeout,
+        terminate(stream, listener),
+    )
+    .await?;
+    log::debug!(
+        "TLS terminated connect to \"{}:{}\"",
+        context.host,
+        context.port
+    );
+    let route = chain::route(&context, &listener.chain, listener).await;
+    let _permit = limits::admit(&route, &context)?;
+    let mut proxy = route.connect(&context).await?;
+    if listener.upstream_tls {
+        let mut proxy = timeout::run(
+            Phase::Handshake,
+            route.timeouts.handshake,
+            tls::connect(proxy, &context.host, &config::TlsClient::default()),
+        )
+        .await?;
+        relay::copy_bidirectional(&mut stream, &mut proxy, route.timeouts.idle, &route.limits)
+            .await?;
+    } else {
+        relay::copy_bidirectional(&mut stream, &mut proxy, route.timeouts.idle, &route.limits)
+            .await?;
+    }
+    Ok(())
+}
+
+// The ClientHello is sniffed first so that routing sees the same metadata as
+// on pass-through listeners, then replayed into rustls.
+async fn terminate(
+    mut stream: TcpStream,
+    listener: &args::Listener,
+) -> anyhow::Result<(chain::Context, server::TlsStream<Prefixed<TcpStream>>)> {
+    let (client_hello, buffered) = client_hello::read(&mut stream).await?;
+    let context = tls_proxy::context(&stream, client_hello, listener)?;
+    let stream = tls::acceptor()
+        .accept(Prefixed::new(buffered, stream))
+        .await?;
+    Ok((context, stream))
+}
+
+#[tokio::test]
+async fn terminate_test() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    tls::install(&tls::test_settings()).await;
+    let server = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = server.local_addr().unwrap();
+    let listener: args::Listener = format!("kind=tls-terminate,addr={},chain=tls", address)
+        .parse()
+        .unwrap();
+    let settings = config::TlsClient {
+        ca: Some(std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/ca.pem")),
+        ..Default::default()
+    };
+
+    let client = tokio::spawn({
+        let settings = settings.clone();
+        async move {
+            let stream = TcpStream::connect(address).await.unwrap();
+            let mut stream = tls::connect(stream, "www.example.com", &settings)
+                .await
+                .unwrap();
+            stream.write_all(b"ping").await.unwrap();
+            let mut reply = [0; 4];
+            stream.read_exact(&mut reply).await.unwrap();
+            reply
+        }
+    });
+    let (stream, _) = server.accept().await.unwrap();
+    let (context, mut stream) = terminate(stream, &listener).await.unwrap();
+    assert_eq!(context.host, "www.example.com");
+    assert_eq!(context.port, address.port());
+    assert!(context.client.is_some());
+    let mut request = [0; 4];
+    stream.read_exact(&mut request).await.unwrap();
+    assert_eq!(&request, b"ping");
+    stream.write_all(b"pong").await.unwrap();
+    stream.flush().await.unwrap();
+    assert_eq!(&client.await.unwrap(), b"pong");
+
+    // No certificate is configured for this name, so the handshake fails.
+    let client = tokio::spawn(async move {
+        let stream = TcpStream::connect(address).await.unwrap();
+        tls::connect(stream, "example.org", &settings)
+            .await
+            .is_err()
+    });
+    let (stream, _) = server.accept().await.unwrap();
+    assert!(terminate(stream, &listener).await.is_err());
+    assert!(client.await.unwrap());
+}